@@ -0,0 +1,239 @@
+// --cbor: incoming binary messages decoded from CBOR (RFC 8949) to JSON for display, and
+// /cbor's JSON encoded to CBOR. Covers what maps onto JSON; byte strings become hex strings,
+// tags are dropped (their content kept) and undefined becomes null
+
+use serde_json::{Map, Number, Value};
+
+// Nesting beyond this is treated as garbage rather than risking the stack
+const MAX_DEPTH: usize = 128;
+
+// The payload as JSON; None unless it is exactly one well-formed CBOR item
+pub fn decode(data: &[u8]) -> Option<Value> {
+    let mut decoder = Decoder { data, pos: 0 };
+    let value = decoder.item(0)?;
+    (decoder.pos == data.len()).then_some(value)
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    // The argument after the initial byte; None for 31 (indefinite length)
+    fn argument(&mut self, info: u8) -> Option<Option<u64>> {
+        let value = match info {
+            0..=23 => u64::from(info),
+            24 => u64::from(self.byte()?),
+            25 => u64::from(u16::from_be_bytes(self.take(2)?.try_into().ok()?)),
+            26 => u64::from(u32::from_be_bytes(self.take(4)?.try_into().ok()?)),
+            27 => u64::from_be_bytes(self.take(8)?.try_into().ok()?),
+            31 => return Some(None),
+            _ => return None,
+        };
+        Some(Some(value))
+    }
+
+    // Consume the 0xff that ends an indefinite-length item, if it is next
+    fn at_break(&mut self) -> bool {
+        let found = self.data.get(self.pos) == Some(&0xff);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn item(&mut self, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let initial = self.byte()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        if major == 7 {
+            return self.simple(info);
+        }
+        let argument = self.argument(info)?;
+        match (major, argument) {
+            (0, Some(n)) => Some(Value::from(n)),
+            (1, Some(n)) => Some(match i64::try_from(n) {
+                Ok(n) => Value::from(-1 - n),
+                Err(_) => Value::from(-1.0 - n as f64),
+            }),
+            (2 | 3, Some(len)) => {
+                let bytes = self.take(usize::try_from(len).ok()?)?.to_vec();
+                string(major, bytes)
+            }
+            // Indefinite-length string: definite-length chunks of the same type until the break
+            (2 | 3, None) => {
+                let mut bytes = Vec::new();
+                while !self.at_break() {
+                    let chunk = self.byte()?;
+                    if chunk >> 5 != major {
+                        return None;
+                    }
+                    let len = self.argument(chunk & 0x1f)??;
+                    bytes.extend_from_slice(self.take(usize::try_from(len).ok()?)?);
+                }
+                string(major, bytes)
+            }
+            (4, len) => {
+                let mut items = Vec::new();
+                while match len {
+                    Some(len) => (items.len() as u64) < len,
+                    None => !self.at_break(),
+                } {
+                    items.push(self.item(depth + 1)?);
+                }
+                Some(Value::Array(items))
+            }
+            (5, len) => {
+                let mut map = Map::new();
+                let mut count = 0;
+                while match len {
+                    Some(len) => count < len,
+                    None => !self.at_break(),
+                } {
+                    // JSON keys are strings: other keys are written as their JSON text
+                    let key = match self.item(depth + 1)? {
+                        Value::String(key) => key,
+                        key => key.to_string(),
+                    };
+                    let value = self.item(depth + 1)?;
+                    map.insert(key, value);
+                    count += 1;
+                }
+                Some(Value::Object(map))
+            }
+            (6, Some(_)) => self.item(depth + 1),
+            _ => None,
+        }
+    }
+
+    fn simple(&mut self, info: u8) -> Option<Value> {
+        match info {
+            20 => Some(Value::Bool(false)),
+            21 => Some(Value::Bool(true)),
+            22 | 23 => Some(Value::Null),
+            25 => {
+                let bits = u16::from_be_bytes(self.take(2)?.try_into().ok()?);
+                Some(float(half_to_f64(bits)))
+            }
+            26 => {
+                let bits = u32::from_be_bytes(self.take(4)?.try_into().ok()?);
+                Some(float(f64::from(f32::from_bits(bits))))
+            }
+            27 => {
+                let bits = u64::from_be_bytes(self.take(8)?.try_into().ok()?);
+                Some(float(f64::from_bits(bits)))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Text must be UTF-8; bytes are shown as hex
+fn string(major: u8, bytes: Vec<u8>) -> Option<Value> {
+    if major == 3 {
+        String::from_utf8(bytes).ok().map(Value::String)
+    } else {
+        Some(Value::String(
+            bytes.iter().map(|b| format!("{b:02x}")).collect(),
+        ))
+    }
+}
+
+// NaN and infinities have no JSON form
+fn float(value: f64) -> Value {
+    Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+// IEEE 754 half precision (RFC 8949 appendix D)
+fn half_to_f64(bits: u16) -> f64 {
+    let exponent = i32::from((bits >> 10) & 0x1f);
+    let mantissa = f64::from(bits & 0x3ff);
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent - 25),
+    };
+    if bits & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+fn encode_into(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                head(0, n, out);
+            } else if let Some(n) = n.as_i64() {
+                // Negative: major type 1 holds -1 - n
+                head(1, (-1 - n) as u64, out);
+            } else {
+                out.push(0xfb);
+                out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Value::String(text) => {
+            head(3, text.len() as u64, out);
+            out.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            head(4, items.len() as u64, out);
+            for item in items {
+                encode_into(item, out);
+            }
+        }
+        Value::Object(map) => {
+            head(5, map.len() as u64, out);
+            for (key, value) in map {
+                head(3, key.len() as u64, out);
+                out.extend_from_slice(key.as_bytes());
+                encode_into(value, out);
+            }
+        }
+    }
+}
+
+// Initial byte and argument in the shortest form
+fn head(major: u8, n: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
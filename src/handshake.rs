@@ -0,0 +1,256 @@
+// Errors are tungstenite's own, so callers see the same variants as from connect_async
+#![allow(clippy::result_large_err)]
+
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::{HeaderName, HeaderValue, StatusCode};
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::error::{Error, ProtocolError, UrlError};
+use tokio_tungstenite::tungstenite::handshake::client::{generate_request, Request, Response};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+// Host (without IPv6 brackets) and port the request targets
+fn target(request: &Request) -> Result<(String, u16), Error> {
+    let uri = request.uri();
+    let host = uri
+        .host()
+        .ok_or(Error::Url(UrlError::NoHostName))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let default_port = if uri.scheme_str() == Some("wss") {
+        443
+    } else {
+        80
+    };
+    Ok((host, uri.port_u16().unwrap_or(default_port)))
+}
+
+// Explicit-socket path: open the TCP stream ourselves instead of letting tokio-tungstenite
+// do it, so the socket can be customized and the handshake written by hand. With `bind`,
+// the socket gets that local address first (only target addresses of its family are tried).
+// `resolved` (--resolve) replaces the DNS lookup of the host
+pub async fn connect_tcp(
+    request: &Request,
+    resolved: Option<IpAddr>,
+    bind: Option<SocketAddr>,
+) -> Result<TcpStream, Error> {
+    let (host, port) = target(request)?;
+    let remotes: Vec<SocketAddr> = match resolved {
+        Some(ip) => vec![SocketAddr::new(ip, port)],
+        None => lookup_host((host.as_str(), port)).await?.collect(),
+    };
+    let Some(local) = bind else {
+        return Ok(TcpStream::connect(remotes.as_slice()).await?);
+    };
+
+    let mut last_err = None;
+    for remote in remotes {
+        if remote.is_ipv4() != local.is_ipv4() {
+            continue;
+        }
+        let socket = if local.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(local).map_err(|err| {
+            std::io::Error::new(err.kind(), format!("failed to bind to {local}: {err}"))
+        })?;
+        match socket.connect(remote).await {
+            Ok(tcp) => return Ok(tcp),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(Error::Io(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{host} has no address of the same family as --bind {local}"),
+        )
+    })))
+}
+
+// Use an inherited, already connected TCP socket instead of connecting. Unix domain sockets
+// are refused: the stream is a TcpStream, and peer_addr/set_nodelay don't work on them.
+// The stream takes ownership of the fd, so call this at most once per fd
+#[cfg(unix)]
+pub fn tcp_from_fd(fd: std::os::fd::RawFd) -> Result<TcpStream, Error> {
+    use std::os::fd::FromRawFd;
+
+    let mut sock_type: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: getsockopt only writes an int into sock_type; an invalid fd just fails
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut sock_type as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 || sock_type != libc::SOCK_STREAM {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--fd {fd} is not a stream socket"),
+        )));
+    }
+
+    // SAFETY: sockaddr_storage is plain data, for which all zeroes is a valid value
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: getsockname writes at most len bytes into addr; an invalid fd just fails
+    let rc = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    let family = libc::c_int::from(addr.ss_family);
+    if rc != 0 || (family != libc::AF_INET && family != libc::AF_INET6) {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("--fd {fd} is not a TCP socket"),
+        )));
+    }
+
+    // SAFETY: fd is a valid TCP socket, and the caller hands it over only once, so it is ours
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    stream.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(stream)?)
+}
+
+// Wrap an open socket in TLS when a config is given (wss), using the request's host for SNI
+pub async fn wrap_tls(
+    tcp: TcpStream,
+    request: &Request,
+    tls_config: Option<Arc<ClientConfig>>,
+) -> Result<MaybeTlsStream<TcpStream>, Error> {
+    match tls_config {
+        Some(config) => {
+            let (host, _) = target(request)?;
+            let name = ServerName::try_from(host).map_err(|_| Error::Url(UrlError::NoHostName))?;
+            let tls = TlsConnector::from(config).connect(name, tcp).await?;
+            Ok(MaybeTlsStream::Rustls(tls))
+        }
+        None => Ok(MaybeTlsStream::Plain(tcp)),
+    }
+}
+
+// Write the upgrade request ourselves. `raw_headers` are appended verbatim, which keeps the
+// user's header casing (the typed header map always lowercases names). A non-zero
+// `byte_delay` sends the request one byte at a time (testing server handshake timeouts)
+pub async fn client_handshake<S>(
+    mut stream: S,
+    request: Request,
+    raw_headers: &[(String, HeaderValue)],
+    byte_delay: Duration,
+) -> Result<(WebSocketStream<S>, Response), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut bytes, key) = generate_request(request)?;
+    // Drop the terminating blank line, append the raw headers, then terminate again
+    bytes.truncate(bytes.len() - 2);
+    for (name, value) in raw_headers {
+        write!(bytes, "{name}: ").unwrap();
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
+    }
+    bytes.extend_from_slice(b"\r\n");
+
+    if byte_delay.is_zero() {
+        stream.write_all(&bytes).await?;
+    } else {
+        for byte in &bytes {
+            stream.write_all(std::slice::from_ref(byte)).await?;
+            stream.flush().await?;
+            tokio::time::sleep(byte_delay).await;
+        }
+    }
+    stream.flush().await?;
+
+    // Read until the full response head is in; anything after it already belongs to the WebSocket
+    let mut buf = Vec::new();
+    let (response, tail) = loop {
+        let mut chunk = [0u8; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::Protocol(ProtocolError::HandshakeIncomplete));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some((response, len)) = parse_response(&buf)? {
+            break (response, buf.split_off(len));
+        }
+    };
+
+    let response = verify_response(response, &key)?;
+
+    let ws_stream = WebSocketStream::from_partially_read(stream, tail, Role::Client, None).await;
+    Ok((ws_stream, response))
+}
+
+// Parse the response head; None means more bytes are needed
+fn parse_response(buf: &[u8]) -> Result<Option<(Response, usize)>, Error> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let len = match parsed.parse(buf)? {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Ok(None),
+    };
+
+    let mut response = Response::new(None);
+    *response.status_mut() = StatusCode::from_u16(parsed.code.unwrap_or_default())?;
+    for header in parsed.headers.iter() {
+        response.headers_mut().append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+    Ok(Some((response, len)))
+}
+
+// Same checks tungstenite does for its own handshake (RFC 6455 section 4.1)
+fn verify_response(response: Response, key: &str) -> Result<Response, Error> {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(Error::Http(response));
+    }
+
+    let headers = response.headers();
+    let has_token = |name: &str, token: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    };
+    if !has_token("Upgrade", "websocket") {
+        return Err(Error::Protocol(
+            ProtocolError::MissingUpgradeWebSocketHeader,
+        ));
+    }
+    if !has_token("Connection", "Upgrade") {
+        return Err(Error::Protocol(
+            ProtocolError::MissingConnectionUpgradeHeader,
+        ));
+    }
+
+    let accept_key = derive_accept_key(key.as_bytes());
+    if headers.get("Sec-WebSocket-Accept").map(|v| v.as_bytes()) != Some(accept_key.as_bytes()) {
+        return Err(Error::Protocol(
+            ProtocolError::SecWebSocketAcceptKeyMismatch,
+        ));
+    }
+
+    Ok(response)
+}
@@ -0,0 +1,259 @@
+// Lines to send: stdin, or the file given with --input. A FIFO is reopened at EOF, so
+// several writers can take turns without ending the session. With --char-mode the input is
+// forwarded in whatever chunks it arrives, instead of by line. --max-line-length bounds how
+// much of a line is buffered, and --timestamp-field holds each line back until it is due.
+// --replay-loop / --replay-loops read a regular file again from the start when it ends
+
+use std::io;
+use std::path::PathBuf;
+
+use chrono::DateTime;
+use regex::Regex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+// Lines read ahead of the session loop; keeps backpressure on the source
+const INPUT_BUFFER: usize = 16;
+
+// --max-line-length: the most bytes a line may have; a longer one is an error, or with
+// --truncate-input is cut to fit
+#[derive(Clone, Copy, Debug)]
+pub struct LineLimit {
+    pub max: usize,
+    pub truncate: bool,
+}
+
+// --timestamp-field: replays input with the gaps between the timestamps of its lines
+pub struct Pacer {
+    field: Regex,
+    // The previous timestamp (in seconds) and when its line was due
+    last: Option<(f64, Instant)>,
+}
+
+impl Pacer {
+    pub fn new(field: Regex) -> Self {
+        Self { field, last: None }
+    }
+
+    // A new pass over the file: its first line goes right away
+    fn restart(&mut self) {
+        self.last = None;
+    }
+
+    // Wait until the line is due: as long after the previous one as its timestamp is later.
+    // A line without a usable timestamp, or one earlier than the previous, goes right away
+    async fn wait(&mut self, line: &str) {
+        let Some(timestamp) = self.timestamp(line) else {
+            crate::warn("no timestamp in input line (--timestamp-field), sent without delay");
+            return;
+        };
+        let now = Instant::now();
+        let due = match self.last {
+            Some((last, _)) if timestamp < last => {
+                crate::warn(
+                    "input timestamp goes backwards (--timestamp-field), sent without delay",
+                );
+                now
+            }
+            Some((last, at)) => at + Duration::from_secs_f64(timestamp - last),
+            None => now,
+        };
+        tokio::time::sleep_until(due).await;
+        // From when it was due, not from now, so delays don't add up
+        self.last = Some((timestamp, due));
+    }
+
+    // The first capture group of the match (or all of it): RFC 3339, or Unix seconds
+    fn timestamp(&self, line: &str) -> Option<f64> {
+        let captures = self.field.captures(line)?;
+        let text = captures.get(1).or_else(|| captures.get(0))?.as_str();
+        match DateTime::parse_from_rfc3339(text) {
+            Ok(time) => Some(time.timestamp_micros() as f64 / 1e6),
+            Err(_) => text.parse::<f64>().ok().filter(|secs| secs.is_finite()),
+        }
+    }
+}
+
+// Read in a task of its own: opening a FIFO waits for a writer, which must not hold up the
+// session loop (and must survive its select! dropping the pending read). The channel closes
+// at the end of input
+pub fn spawn(
+    path: Option<PathBuf>,
+    passes: Option<u64>,
+    chunks: bool,
+    limit: Option<LineLimit>,
+    mut pacer: Option<Pacer>,
+) -> mpsc::Receiver<io::Result<String>> {
+    let (tx, rx) = mpsc::channel(INPUT_BUFFER);
+    tokio::spawn(async move {
+        let result = match path {
+            Some(path) => read_path(path, passes, chunks, limit, &mut pacer, &tx).await,
+            None if chunks => forward_chunks(tokio::io::stdin(), &tx).await,
+            None => forward(tokio::io::stdin(), limit, &mut pacer, &tx).await,
+        };
+        if let Err(err) = result {
+            let _ = tx.send(Err(err)).await;
+        }
+    });
+    rx
+}
+
+// passes: how many times a regular file is read, or None to read it until the session ends
+async fn read_path(
+    path: PathBuf,
+    passes: Option<u64>,
+    chunks: bool,
+    limit: Option<LineLimit>,
+    pacer: &mut Option<Pacer>,
+    tx: &mpsc::Sender<io::Result<String>>,
+) -> io::Result<()> {
+    let reopen = is_fifo(&path)?;
+    // An empty file would just spin
+    let passes = match tokio::fs::metadata(&path).await {
+        Ok(meta) if meta.is_file() && meta.len() == 0 => Some(1),
+        _ => passes,
+    };
+    let mut pass = 1;
+    loop {
+        // For a FIFO this waits until a writer opens it
+        let file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+        if chunks {
+            forward_chunks(file, tx).await?;
+        } else {
+            forward(file, limit, pacer, tx).await?;
+        }
+        let again = reopen || passes.is_none_or(|passes| pass < passes);
+        if !again || tx.is_closed() {
+            return Ok(());
+        }
+        pass += 1;
+        if let Some(pacer) = pacer {
+            pacer.restart();
+        }
+    }
+}
+
+// Send each line until EOF, without its "\n" or "\r\n". Read a buffer at a time rather than
+// with lines(), so a line over the limit is never held in full
+async fn forward(
+    source: impl AsyncRead + Unpin,
+    limit: Option<LineLimit>,
+    pacer: &mut Option<Pacer>,
+    tx: &mpsc::Sender<io::Result<String>>,
+) -> io::Result<()> {
+    let max = limit.map_or(usize::MAX, |limit| limit.max);
+    let mut reader = BufReader::new(source);
+    let mut line = Vec::new();
+    // --truncate-input: the line was cut, skip the rest of it
+    let mut cut = false;
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            // A last line without a newline still counts, an empty one doesn't
+            if !line.is_empty() || cut {
+                let _ = tx.send(paced(finish_line(line, cut), pacer).await).await;
+            }
+            return Ok(());
+        }
+        let end = buf.iter().position(|&b| b == b'\n');
+        let part = &buf[..end.unwrap_or(buf.len())];
+        if !cut {
+            let room = max - line.len();
+            if part.len() > room {
+                if !limit.is_some_and(|limit| limit.truncate) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("input line longer than {max} bytes (--max-line-length)"),
+                    ));
+                }
+                crate::warn(&format!(
+                    "input line cut to {max} bytes (--max-line-length)"
+                ));
+                cut = true;
+            }
+            line.extend_from_slice(&part[..part.len().min(room)]);
+        }
+        let used = part.len() + usize::from(end.is_some());
+        reader.consume(used);
+        if end.is_some() {
+            let line = std::mem::take(&mut line);
+            let line = paced(finish_line(line, std::mem::take(&mut cut)), pacer).await;
+            if tx.send(line).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// The line, once --timestamp-field says it is due
+async fn paced(line: io::Result<String>, pacer: &mut Option<Pacer>) -> io::Result<String> {
+    if let (Ok(line), Some(pacer)) = (&line, pacer) {
+        pacer.wait(line).await;
+    }
+    line
+}
+
+// A line's bytes as text: the "\r" of a "\r\n" dropped, and for a cut line a character split
+// at the cut dropped too
+fn finish_line(mut line: Vec<u8>, cut: bool) -> io::Result<String> {
+    if cut {
+        if let Err(err) = std::str::from_utf8(&line) {
+            if err.error_len().is_none() {
+                line.truncate(err.valid_up_to());
+            }
+        }
+    } else if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stream did not contain valid UTF-8",
+        )
+    })
+}
+
+// Send each read as it completes. A UTF-8 sequence split across reads is held back until the
+// rest of it arrives; invalid bytes are replaced
+async fn forward_chunks(
+    mut source: impl AsyncRead + Unpin,
+    tx: &mpsc::Sender<io::Result<String>>,
+) -> io::Result<()> {
+    let mut pending = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = source.read(&mut buf).await?;
+        pending.extend_from_slice(&buf[..n]);
+        let complete = match std::str::from_utf8(&pending) {
+            Ok(_) => pending.len(),
+            Err(err) if n > 0 && err.error_len().is_none() => err.valid_up_to(),
+            Err(_) => pending.len(),
+        };
+        if complete > 0 {
+            let chunk = String::from_utf8_lossy(&pending[..complete]).into_owned();
+            pending.drain(..complete);
+            if tx.send(Ok(chunk)).await.is_err() {
+                break;
+            }
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &std::path::Path) -> io::Result<bool> {
+    use std::os::unix::fs::FileTypeExt;
+
+    Ok(std::fs::metadata(path)?.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &std::path::Path) -> io::Result<bool> {
+    Ok(false)
+}
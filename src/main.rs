@@ -1,44 +1,1073 @@
+mod cbor;
+mod handshake;
+mod input;
+mod rng;
+mod schedule;
+mod server;
+mod status;
+mod template;
+mod wire;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs;
+use std::io::{IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-use clap::{CommandFactory, Parser};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser, ValueEnum};
+use futures_util::stream::SplitStream;
 use futures_util::{SinkExt, StreamExt};
 use http::HeaderValue;
+use input::{LineLimit, Pacer};
+use regex::Regex;
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{ResolvesClientCert, WebPkiServerVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::sign::CertifiedKey;
 use rustls::{ClientConfig, RootCertStore};
-use tokio::io::{AsyncBufReadExt, BufReader};
+use schedule::{Ramp, Schedule};
+use sha2::{Digest, Sha256};
+use status::{Metered, SummarySignal, Traffic};
+use template::Template;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Response;
 use tokio_tungstenite::tungstenite::http::HeaderName;
-use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{CloseCode, Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::Frame;
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use wire::Wire;
+
+// Client connection as opened by connect(): TCP (or an inherited fd), optional TLS, then the
+// byte-level Wire layer
+type WsStream = WebSocketStream<Wire<MaybeTlsStream<TcpStream>>>;
 
 // CLI options (connect-only subset)
 #[derive(Parser, Debug)]
 #[command(name = "wscrab", version, about = "WebSocket cat (Rust subset)")]
+// --reconnect, or --resilient which implies it
+#[command(group(ArgGroup::new("reconnecting").multiple(true)))]
+// --every or --send-rate-ramp, not both
+#[command(group(ArgGroup::new("schedule")))]
+// --ping-interval and/or --inactivity-ping
+#[command(group(ArgGroup::new("pinging").multiple(true)))]
 struct Opts {
     #[arg(long, short = 'c', help = "Connect to a WebSocket server")]
     connect: Option<String>,
 
-    #[arg(long, help = "Client certificate file (PEM/DER)")]
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Role::Client,
+        requires = "connect",
+        conflicts_with_all = ["echo_server", "listen"],
+        help = "Side of the --connect URL to take: client, server (listen on its port, like --listen), or auto (connect if something accepts TCP there, listen otherwise)"
+    )]
+    role: Role,
+
+    #[cfg(unix)]
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["reconnect", "reconnect_on_idle", "resilient"],
+        help = "Run the handshake over inherited fd N (a connected TCP socket) instead of connecting; --connect still sets Host and path"
+    )]
+    fd: Option<i32>,
+
+    #[arg(
+        long,
+        value_name = "IP[:PORT]",
+        value_parser = parse_bind,
+        help = "Connect from this local address (port 0 or omitted picks any)"
+    )]
+    bind: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_name = "HOST:IP",
+        value_parser = parse_resolve,
+        help = "Connect to IP whenever the URL's host is HOST, keeping HOST for the Host header and TLS (repeatable)"
+    )]
+    resolve: Vec<(String, IpAddr)>,
+
+    #[arg(
+        long = "echo-server",
+        value_name = "PORT",
+        conflicts_with = "connect",
+        help = "Run a WebSocket echo server on PORT instead of connecting"
+    )]
+    echo_server: Option<u16>,
+
+    #[arg(
+        long,
+        requires = "echo_server",
+        help = "Serve a single client, then exit"
+    )]
+    once: bool,
+
+    #[arg(
+        long,
+        value_name = "PORT",
+        conflicts_with_all = ["connect", "echo_server"],
+        help = "Listen on PORT: print what clients send and send them stdin lines"
+    )]
+    listen: Option<u16>,
+
+    #[arg(
+        long,
+        requires = "listen",
+        help = "With --listen, serve several clients and relay every message to all of them"
+    )]
+    broadcast: bool,
+
+    #[arg(
+        long = "wait-for-open",
+        requires = "listen",
+        help = "With --listen, keep stdin lines typed while no client is connected and send them once one connects"
+    )]
+    wait_for_open: bool,
+
+    #[arg(
+        long,
+        help = "Client certificate file (PEM/DER), or a directory/mapping file of per-host identities; with --listen, the server certificate and key (serves wss://)"
+    )]
     cert: Option<PathBuf>,
 
+    #[arg(
+        long = "cert-reload",
+        requires = "cert",
+        help = "Re-read --cert on every (re)connect, to pick up rotated client certificates"
+    )]
+    cert_reload: bool,
+
     #[arg(long = "header", short = 'H', help = "Set an HTTP header (repeatable)")]
     header: Vec<String>,
 
+    #[arg(
+        long = "expand-placeholders",
+        help = "Replace {host}, {port} and {path} in -H values with parts of the connect URL"
+    )]
+    expand_placeholders: bool,
+
+    #[arg(
+        long = "header-case-sensitive",
+        help = "Send -H header names with their exact casing (for non-compliant servers)"
+    )]
+    header_case_sensitive: bool,
+
+    #[arg(
+        long = "handshake-header-order",
+        help = "Send -H headers in the exact order given, repeats included, after the standard upgrade headers (interop testing)"
+    )]
+    handshake_header_order: bool,
+
+    #[arg(long, short = 'o', help = "Set the Origin header")]
+    origin: Option<String>,
+
+    #[arg(
+        long = "auto-origin",
+        help = "Derive the Origin header from the connect URL (http(s)://host[:port])"
+    )]
+    auto_origin: bool,
+
+    #[arg(
+        long = "client-id",
+        value_name = "ID",
+        help = "Send X-Client-Id: ID and prefix diagnostic lines with [ID]"
+    )]
+    client_id: Option<String>,
+
+    #[arg(
+        long = "trace-header",
+        value_name = "NAME[:VALUE]",
+        value_parser = parse_trace_header,
+        help = "Send a request ID header for tracing, with VALUE or a fresh UUID per connection, and print it to stderr"
+    )]
+    trace_header: Option<TraceHeader>,
+
+    #[arg(
+        long = "challenge-response",
+        value_name = "hmac-sha256:SECRET",
+        value_parser = parse_challenge_response,
+        help = "Answer the first incoming message of each connection with the hex HMAC-SHA256 of its payload, keyed with SECRET; input waits until then"
+    )]
+    challenge_response: Option<String>,
+
+    #[arg(
+        long = "print-url",
+        help = "Print the final connect URL (after adding a default scheme) to stderr"
+    )]
+    print_url: bool,
+
+    #[arg(
+        long,
+        short = 's',
+        value_name = "PROTOCOL",
+        help = "Offer a subprotocol via Sec-WebSocket-Protocol (repeatable)"
+    )]
+    subprotocol: Vec<String>,
+
+    #[arg(
+        long = "require-subprotocol",
+        requires = "subprotocol",
+        help = "Exit with code 4 if the server selects no subprotocol (default: warn)"
+    )]
+    require_subprotocol: bool,
+
+    #[arg(
+        long = "print-response-header",
+        value_name = "NAME",
+        conflicts_with = "raw_output",
+        help = "After connecting, print the value of this handshake response header on its own line (repeatable)"
+    )]
+    print_response_header: Vec<String>,
+
+    #[arg(
+        long = "handshake-only",
+        help = "Health probe: complete the upgrade, print the status and subprotocol, close cleanly and exit without reading input"
+    )]
+    handshake_only: bool,
+
+    #[arg(
+        long = "print-handshake-latency",
+        help = "Print the time from starting to connect to the completed handshake to stderr (\"handshake: 87ms\")"
+    )]
+    print_handshake_latency: bool,
+
+    #[arg(
+        long = "emit-connect-event",
+        conflicts_with = "raw_output",
+        help = "On every connect, print one JSON line to stdout with the URL, subprotocol, TLS version and cipher, peer address and handshake time"
+    )]
+    emit_connect_event: bool,
+
     #[arg(long = "no-check", help = "Skip server certificate verification")]
     no_check: bool,
 
+    #[arg(
+        long = "insecure-hosts",
+        value_name = "HOSTS",
+        value_delimiter = ',',
+        conflicts_with_all = ["no_check", "pin_sha256"],
+        help = "Skip server certificate verification for these hosts only (comma-separated); all others are verified"
+    )]
+    insecure_hosts: Vec<String>,
+
+    #[arg(
+        long = "pin-sha256",
+        value_name = "BASE64",
+        value_parser = parse_pin,
+        conflicts_with = "no_check",
+        help = "Only accept a server certificate whose DER has this SHA-256 (base64), ignoring the CA chain"
+    )]
+    pin_sha256: Option<[u8; 32]>,
+
+    #[arg(
+        long = "verify-depth",
+        value_name = "N",
+        conflicts_with_all = ["no_check", "pin_sha256"],
+        help = "Reject server certificate chains with more than N intermediates"
+    )]
+    verify_depth: Option<usize>,
+
+    #[arg(
+        long = "print-cipher",
+        help = "Print the negotiated TLS version and cipher suite to stderr (wss only)"
+    )]
+    print_cipher: bool,
+
+    #[arg(
+        long = "trust-system-store",
+        conflicts_with_all = ["no_check", "pin_sha256"],
+        help = "Also trust the CA certificates installed in the OS certificate store"
+    )]
+    trust_system_store: bool,
+
+    #[arg(
+        long = "allow-downgrade",
+        help = "If the wss:// TLS handshake is refused, retry once over plain ws:// (insecure)"
+    )]
+    allow_downgrade: bool,
+
     #[arg(long = "show-ping-pong", help = "Print notifications for ping/pong")]
     show_ping_pong: bool,
 
-    #[arg(long, help = "Enable slash commands (/ping, /pong, /close)")]
+    #[arg(
+        long = "auto-pong-data",
+        value_name = "MODE",
+        value_parser = parse_pong_data,
+        default_value = "echo",
+        help = "Payload of the automatic reply to a server ping: echo (the ping's data), empty, or any other string to send as is"
+    )]
+    auto_pong_data: PongData,
+
+    #[arg(
+        long = "ping-interval",
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        group = "pinging",
+        help = "Send a keep-alive ping every SECS seconds"
+    )]
+    ping_interval: Option<u64>,
+
+    #[arg(
+        long = "inactivity-ping",
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        group = "pinging",
+        help = "Send a ping once nothing has been sent or received for SECS seconds"
+    )]
+    inactivity_ping: Option<u64>,
+
+    #[arg(
+        long = "ping-payload",
+        value_name = "DATA",
+        value_parser = parse_ping_payload,
+        requires = "pinging",
+        help = "Data carried by each --ping-interval / --inactivity-ping ping: a string, or hex:<digits> for raw bytes (default: empty)"
+    )]
+    ping_payload: Option<PingPayload>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read lines to send from PATH instead of stdin; a FIFO is reopened at EOF instead of ending the session"
+    )]
+    input: Option<PathBuf>,
+
+    #[arg(
+        long = "replay-loop",
+        requires = "input",
+        help = "Read the --input file again from the start each time it ends, until the session is stopped"
+    )]
+    replay_loop: bool,
+
+    #[arg(
+        long = "replay-loops",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "input",
+        conflicts_with = "replay_loop",
+        help = "Read the --input file N times over, then end as usual"
+    )]
+    replay_loops: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["input", "char_mode"],
+        help = "Send the lines of PATH one at a time, each only once an incoming message has answered the previous one"
+    )]
+    conversation: Option<PathBuf>,
+
+    #[arg(
+        long = "conversation-timeout",
+        value_name = "MS",
+        requires = "conversation",
+        help = "Fail (exit code 3) when a --conversation line gets no reply within MS milliseconds"
+    )]
+    conversation_timeout: Option<u64>,
+
+    #[arg(
+        long = "timestamp-field",
+        value_name = "REGEX",
+        conflicts_with = "char_mode",
+        help = "Replay input with its original timing: wait between lines as long as the timestamps REGEX finds in them (its first group, RFC 3339 or Unix seconds) are apart"
+    )]
+    timestamp_field: Option<Regex>,
+
+    #[arg(
+        long = "max-line-length",
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "char_mode",
+        help = "Fail on an input line longer than BYTES instead of buffering it (see --truncate-input)"
+    )]
+    max_line_length: Option<u64>,
+
+    #[arg(
+        long = "truncate-input",
+        requires = "max_line_length",
+        help = "Cut input lines longer than --max-line-length to fit, with a warning, instead of failing"
+    )]
+    truncate_input: bool,
+
+    #[arg(
+        long = "char-mode",
+        conflicts_with_all = ["slash", "message_prefix", "message_suffix", "input_prefix_strip", "input_json_lines"],
+        help = "Send input as it is typed, each keystroke (or chunk of piped input) as its own text message, without local echo"
+    )]
+    char_mode: bool,
+
+    #[arg(
+        long = "after-connect-delay",
+        value_name = "MS",
+        help = "Wait MS milliseconds after connecting before sending the first line"
+    )]
+    after_connect_delay: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        group = "schedule",
+        requires = "every_message",
+        help = "Send the --every-message text every MS milliseconds (e.g. an app-level heartbeat)"
+    )]
+    every: Option<u64>,
+
+    #[arg(
+        long = "send-rate-ramp",
+        value_name = "START:END:SECS",
+        value_parser = parse_ramp,
+        group = "schedule",
+        requires = "every_message",
+        help = "Send the --every-message text at a rate going linearly from START to END messages per second over SECS seconds, then staying at END"
+    )]
+    send_rate_ramp: Option<Ramp>,
+
+    #[arg(
+        long = "every-message",
+        value_name = "TEXT",
+        requires = "schedule",
+        help = "Text message sent on the --every or --send-rate-ramp schedule"
+    )]
+    every_message: Option<String>,
+
+    #[arg(
+        long = "every-jitter",
+        value_name = "FRACTION",
+        value_parser = parse_jitter,
+        default_value_t = 0.0,
+        requires = "schedule",
+        help = "Vary each --every interval randomly by up to this fraction either way (0 to 1), so clients don't send in lockstep"
+    )]
+    every_jitter: f64,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        help = "Send TEXT after connecting, filling in {n} (send index), {ts} (Unix time in ms) and {uuid} (random UUID) on every send"
+    )]
+    template: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        default_value_t = 1,
+        requires = "template",
+        help = "Send the --template message N times, rendered afresh each time"
+    )]
+    repeat: u64,
+
+    #[arg(
+        long = "input-prefix-strip",
+        value_name = "REGEX",
+        value_parser = parse_prefix_strip,
+        help = "Remove a leading match of REGEX from every line sent from stdin, before --message-prefix (lines that don't match are sent as is)"
+    )]
+    input_prefix_strip: Option<Regex>,
+
+    #[arg(
+        long = "message-prefix",
+        value_name = "TEXT",
+        help = "Prepend TEXT to every line sent from stdin (not to slash commands)"
+    )]
+    message_prefix: Option<String>,
+
+    #[arg(
+        long = "message-suffix",
+        value_name = "TEXT",
+        help = "Append TEXT to every line sent from stdin (not to slash commands)"
+    )]
+    message_suffix: Option<String>,
+
+    #[arg(
+        long = "input-json-lines",
+        help = "Parse each line sent from stdin as JSON and send it compacted; invalid lines are reported and not sent"
+    )]
+    input_json_lines: bool,
+
+    #[arg(
+        long = "strict-json",
+        requires = "input_json_lines",
+        help = "With --input-json-lines, end the session on an invalid line instead of skipping it"
+    )]
+    strict_json: bool,
+
+    #[arg(
+        long = "raw-output",
+        conflicts_with_all = ["dedup", "show_ping_pong"],
+        help = "Write received payloads to stdout as raw bytes, without prefixes or the banner"
+    )]
+    raw_output: bool,
+
+    #[arg(
+        long = "output-framing",
+        value_enum,
+        value_name = "FRAMING",
+        default_value_t = OutputFraming::None,
+        requires = "raw_output",
+        help = "How --raw-output separates messages: none, or length-prefixed (4-byte big-endian length before each payload)"
+    )]
+    output_framing: OutputFraming,
+
+    #[arg(
+        long = "output-split",
+        value_name = "DIR",
+        conflicts_with = "raw_output",
+        help = "Write each incoming message to its own numbered file in DIR (000001.bin, 000002.bin, ...) instead of printing it"
+    )]
+    output_split: Option<PathBuf>,
+
+    #[arg(
+        long = "output-split-ext",
+        value_name = "EXT",
+        default_value = "bin",
+        requires = "output_split",
+        help = "File extension for --output-split files"
+    )]
+    output_split_ext: String,
+
+    #[arg(
+        long = "output-raw",
+        requires = "output_split",
+        help = "Print incoming messages as usual (per --binary-display, --output-format, ...) while --output-split saves their exact bytes"
+    )]
+    output_raw: bool,
+
+    #[arg(
+        long = "on-message",
+        value_name = "COMMAND",
+        conflicts_with_all = ["raw_output", "output_split"],
+        help = "Run COMMAND (via the shell) for each incoming message, with the payload on its stdin, and print its stdout instead of the message"
+    )]
+    on_message: Option<String>,
+
+    #[arg(
+        long = "on-message-fatal",
+        requires = "on_message",
+        help = "End the session when the --on-message command fails (by default wscrab warns and keeps going)"
+    )]
+    on_message_fatal: bool,
+
+    #[arg(
+        long = "on-reconnect",
+        value_name = "COMMAND",
+        help = "Run COMMAND (via the shell) after each successful reconnect, with the reconnect number as its first argument ($1)"
+    )]
+    on_reconnect: Option<String>,
+
+    #[arg(
+        long = "on-reconnect-fatal",
+        requires = "on_reconnect",
+        help = "End the session when the --on-reconnect command fails (by default wscrab warns and keeps going)"
+    )]
+    on_reconnect_fatal: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "raw_output",
+        help = "Print incoming binary messages in hexdump -C layout"
+    )]
+    hexdump: bool,
+
+    #[arg(
+        long = "hexdump-all",
+        conflicts_with = "raw_output",
+        help = "Like --hexdump, for text messages too"
+    )]
+    hexdump_all: bool,
+
+    #[arg(
+        long = "binary-display",
+        value_enum,
+        value_name = "MODE",
+        default_value_t = BinaryDisplay::Text,
+        conflicts_with_all = ["raw_output", "hexdump", "hexdump_all"],
+        help = "How to print incoming binary messages"
+    )]
+    binary_display: BinaryDisplay,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["raw_output", "hexdump", "hexdump_all", "binary_display", "smart_display"],
+        help = "Decode incoming binary messages as CBOR and print them as pretty JSON (hex if they aren't CBOR); same as --binary-display cbor"
+    )]
+    cbor: bool,
+
+    #[arg(
+        long = "smart-display",
+        conflicts_with_all = ["raw_output", "hexdump", "hexdump_all"],
+        help = "Print binary messages that are valid UTF-8 as text, the rest in --binary-display mode (hexdump unless set)"
+    )]
+    smart_display: bool,
+
+    #[arg(
+        long = "output-format",
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t = OutputFormat::Text,
+        conflicts_with_all = ["raw_output", "hexdump", "hexdump_all", "binary_display", "cbor", "smart_display", "json_pointer"],
+        help = "How to print every incoming message, text and binary alike: text (binary per --binary-display), json (one envelope object per line), hex or base64"
+    )]
+    output_format: OutputFormat,
+
+    #[arg(
+        long = "payload-size-limit",
+        value_name = "BYTES",
+        help = "Print a placeholder instead of incoming messages larger than BYTES (still counted, and still written by --output-split/--raw-output)"
+    )]
+    payload_size_limit: Option<usize>,
+
+    #[arg(
+        long = "json-pointer",
+        value_name = "POINTER",
+        value_parser = parse_json_pointer,
+        conflicts_with_all = ["raw_output", "hexdump_all"],
+        help = "Print only the value at this JSON Pointer (e.g. /data/status) of each incoming text message; messages without it print nothing"
+    )]
+    json_pointer: Option<String>,
+
+    #[arg(
+        long = "strip-ansi",
+        conflicts_with_all = ["raw_output", "hexdump_all"],
+        help = "Remove ANSI escape sequences (colors, cursor movement) from incoming text before printing"
+    )]
+    strip_ansi: bool,
+
+    #[arg(
+        long = "trim-incoming",
+        help = "Trim leading and trailing whitespace from incoming text before printing and before --assert-receive/--until match it"
+    )]
+    trim_incoming: bool,
+
+    #[arg(
+        long = "drop-incoming",
+        value_name = "KIND",
+        value_enum,
+        help = "Discard incoming messages of this kind without printing them: text, binary, ping or pong (repeatable; pings are still answered)"
+    )]
+    drop_incoming: Vec<MessageKind>,
+
+    #[arg(
+        long = "local-echo-format",
+        value_name = "TEMPLATE",
+        default_value = "> {msg}",
+        help = "How sent lines are echoed: {msg} is the message, {n} its number, {ts} the local time (RFC 3339)"
+    )]
+    local_echo_format: String,
+
+    #[arg(
+        long = "remote-echo-format",
+        value_name = "TEMPLATE",
+        default_value = "< {msg}",
+        help = "How incoming messages are printed, with the same placeholders as --local-echo-format"
+    )]
+    remote_echo_format: String,
+
+    #[arg(
+        long,
+        conflicts_with = "raw_output",
+        help = "Prefix every printed message line, sent or received, with one running sequence number: [47] < ..."
+    )]
+    number: bool,
+
+    #[arg(
+        long,
+        help = "Collapse consecutive identical incoming messages into one line with a repeat count"
+    )]
+    dedup: bool,
+
+    #[arg(
+        long = "buffer-incoming",
+        conflicts_with_all = ["raw_output", "char_mode"],
+        help = "Hold incoming messages until Enter is pressed on an empty line (or /flush), then print them with their arrival offsets: [+12ms] < ..."
+    )]
+    buffer_incoming: bool,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Close and exit 0 once an incoming text message matches"
+    )]
+    until: Option<Regex>,
+
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "Only print incoming messages matching REGEX (binary ones after lossy UTF-8 decoding)"
+    )]
+    grep: Option<Regex>,
+
+    #[arg(
+        long = "grep-invert",
+        requires = "grep",
+        help = "With --grep, only print incoming messages that do NOT match"
+    )]
+    grep_invert: bool,
+
+    #[arg(
+        long = "assert-receive",
+        value_name = "TEXT",
+        help = "Exit 0 only if an incoming text message contains TEXT, else exit with code 5"
+    )]
+    assert_receive: Option<String>,
+
+    #[arg(
+        long = "error-on-match",
+        value_name = "REGEX",
+        help = "Close and exit with code 7 once an incoming text message matches (e.g. the server's error message)"
+    )]
+    error_on_match: Option<Regex>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Close and exit with code 3 after this many seconds, regardless of activity"
+    )]
+    timeout: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "RFC3339",
+        value_parser = parse_deadline,
+        help = "Close and exit 0 at this wall-clock time (e.g. 2024-06-01T12:00:00Z)"
+    )]
+    deadline: Option<DateTime<FixedOffset>>,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Close and exit 0 once no message has arrived for MS milliseconds"
+    )]
+    drain: Option<u64>,
+
+    #[arg(
+        long = "close-timeout",
+        value_name = "MS",
+        default_value_t = 5000,
+        help = "After sending a close frame, wait at most MS milliseconds for the server's close before dropping the connection"
+    )]
+    close_timeout: u64,
+
+    #[arg(
+        long = "close-reason-file",
+        value_name = "PATH",
+        help = "Send the contents of PATH (UTF-8, cut to 123 bytes) as the close reason when ending the session"
+    )]
+    close_reason_file: Option<PathBuf>,
+
+    #[arg(
+        long = "require-clean-close",
+        help = "Exit with code 6 unless the connection ended with a close frame from the server"
+    )]
+    require_clean_close: bool,
+
+    #[arg(
+        long = "connect-timeout",
+        value_name = "SECS",
+        help = "Give up on a connection attempt (TCP, TLS and handshake) after SECS seconds"
+    )]
+    connect_timeout: Option<u64>,
+
+    #[arg(
+        long = "max-redirects",
+        value_name = "N",
+        help = "Follow up to N HTTP redirects answered to the handshake (default: none)"
+    )]
+    max_redirects: Option<u32>,
+
+    #[arg(
+        long,
+        group = "reconnecting",
+        help = "Unattended probe defaults: --max-redirects 5, --reconnect, --reconnect-max 3, --connect-timeout 10 (options given explicitly win)"
+    )]
+    resilient: bool,
+
+    #[arg(
+        long,
+        group = "reconnecting",
+        help = "Reconnect when the server closes the connection or it drops; failed connects are retried with backoff"
+    )]
+    reconnect: bool,
+
+    #[arg(
+        long = "reconnect-max",
+        value_name = "N",
+        requires = "reconnecting",
+        help = "Give up after N failed reconnect attempts in a row (default: retry forever)"
+    )]
+    reconnect_max: Option<u32>,
+
+    #[arg(
+        long = "reconnect-backoff",
+        value_enum,
+        value_name = "STRATEGY",
+        default_value_t = Backoff::Exponential,
+        requires = "reconnecting",
+        help = "How the wait between failed reconnect attempts grows: fixed, linear (delay * attempt) or exponential (doubling)"
+    )]
+    reconnect_backoff: Backoff,
+
+    #[arg(
+        long = "reconnect-delay",
+        value_name = "SECS",
+        default_value_t = 1,
+        requires = "reconnecting",
+        help = "Wait before the first reconnect attempt, and the step --reconnect-backoff grows from"
+    )]
+    reconnect_delay: u64,
+
+    #[arg(
+        long = "reconnect-max-delay",
+        value_name = "SECS",
+        default_value_t = 30,
+        requires = "reconnecting",
+        help = "Cap on the wait between reconnect attempts"
+    )]
+    reconnect_max_delay: u64,
+
+    #[arg(
+        long = "max-reconnects-per-minute",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "reconnecting",
+        help = "Never make more than N reconnect attempts in any 60-second window; waits when the cap is hit"
+    )]
+    max_reconnects_per_minute: Option<u32>,
+
+    #[arg(
+        long = "replay-unsent",
+        requires = "reconnect",
+        help = "After a reconnect, resend the line whose send hadn't completed when the connection dropped (at-least-once)"
+    )]
+    replay_unsent: bool,
+
+    #[arg(
+        long = "max-queued-sends",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "replay_unsent",
+        help = "Queue up to N input lines without waiting for each to be flushed; input is paused while N are pending"
+    )]
+    max_queued_sends: Option<u32>,
+
+    #[arg(
+        long = "reconnect-on-idle",
+        value_name = "SECS",
+        help = "Close and reconnect once no message has arrived for SECS seconds (stale connection)"
+    )]
+    reconnect_on_idle: Option<u64>,
+
+    #[arg(
+        long = "status-line",
+        help = "Show send/receive rates and bytes in flight on a status line (summary at exit when stderr isn't a terminal)"
+    )]
+    status_line: bool,
+
+    #[arg(long, help = "Print message counts to stderr when the session ends")]
+    stats: bool,
+
+    #[arg(
+        long = "summary-on-signal",
+        help = "Print the --stats message counts to stderr on each SIGUSR1 and keep running (Unix)"
+    )]
+    summary_on_signal: bool,
+
+    #[arg(
+        long = "count-frames",
+        requires = "stats",
+        help = "With --stats, also count frames (fragments and control frames included)"
+    )]
+    count_frames: bool,
+
+    #[arg(
+        long = "compression-stats",
+        help = "Print payload bytes against bytes on the wire to stderr when the session ends"
+    )]
+    compression_stats: bool,
+
+    #[arg(
+        long = "pretty-close",
+        help = "On exit, print the server's close code with its registered name and meaning"
+    )]
+    pretty_close: bool,
+
+    #[arg(
+        long = "show-raw-handshake",
+        help = "Print the exact HTTP upgrade request and response bytes to stderr"
+    )]
+    show_raw_handshake: bool,
+
+    // Testing only: violates RFC 6455 on purpose, so it stays out of --help
+    #[arg(long = "no-mask", hide = true)]
+    no_mask: bool,
+
+    // Testing only: probes server handshake timeouts, so it stays out of --help
+    #[arg(long = "slow-handshake", value_name = "MS", hide = true)]
+    slow_handshake: Option<u64>,
+
+    // Testing only: sends a version other than 13 to check the server's 426 reply
+    #[arg(long = "ws-version", value_name = "N", hide = true)]
+    ws_version: Option<u16>,
+
+    #[arg(
+        long = "no-warnings",
+        help = "Don't print advisory warnings (subprotocol not selected, TLS downgrade, --no-mask, ...); errors are still printed"
+    )]
+    no_warnings: bool,
+
+    #[arg(
+        long,
+        help = "Enable slash commands (/ping, /pong, /close, /connect, /repeat, /rawtext, /cbor, /macro, /pause, /resume, /flush)"
+    )]
     slash: bool,
 }
 
+// --binary-display; --hexdump and --raw-output select their mode as well
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum BinaryDisplay {
+    // Lossy UTF-8 decoding
+    Text,
+    Hex,
+    Base64,
+    // hexdump -C layout
+    Hexdump,
+    // CBOR decoded to pretty JSON, hex when it isn't CBOR
+    Cbor,
+    // Payload bytes to stdout unmodified
+    Raw,
+}
+
+// --output-format
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFormat {
+    // Text as is, binary per --binary-display
+    Text,
+    // {"type","size","data"} per message; binary data in base64
+    Json,
+    Hex,
+    Base64,
+}
+
+// --output-framing
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFraming {
+    // Payloads back to back
+    None,
+    LengthPrefixed,
+}
+
+// --role
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Role {
+    Client,
+    Server,
+    Auto,
+}
+
+// --trace-header; without a value, every handshake gets a new UUID
+#[derive(Clone, Debug)]
+struct TraceHeader {
+    name: HeaderName,
+    value: Option<HeaderValue>,
+}
+
+// --ping-payload
+#[derive(Clone, Debug)]
+struct PingPayload(Vec<u8>);
+
+// --drop-incoming
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum MessageKind {
+    Text,
+    Binary,
+    Ping,
+    Pong,
+}
+
+impl MessageKind {
+    fn matches(self, message: &Message) -> bool {
+        matches!(
+            (self, message),
+            (Self::Text, Message::Text(_))
+                | (Self::Binary, Message::Binary(_))
+                | (Self::Ping, Message::Ping(_))
+                | (Self::Pong, Message::Pong(_))
+        )
+    }
+}
+
+// --reconnect-backoff
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Backoff {
+    Fixed,
+    Linear,
+    Exponential,
+}
+
+impl Backoff {
+    // Wait before reconnect attempt `attempt` (1-based), capped at `max`
+    fn delay(self, base: Duration, attempt: u32, max: Duration) -> Duration {
+        let delay = match self {
+            Backoff::Fixed => base,
+            Backoff::Linear => base.saturating_mul(attempt),
+            Backoff::Exponential => {
+                base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            }
+        };
+        delay.min(max)
+    }
+}
+
+impl Opts {
+    // --resilient: fill in whatever the user left unset
+    fn apply_resilient(&mut self) {
+        self.max_redirects.get_or_insert(RESILIENT_REDIRECTS);
+        self.reconnect = true;
+        self.reconnect_max.get_or_insert(RESILIENT_RECONNECT_MAX);
+        self.connect_timeout
+            .get_or_insert(RESILIENT_CONNECT_TIMEOUT);
+    }
+
+    fn binary_display(&self, data: &[u8]) -> BinaryDisplay {
+        if self.raw_output {
+            BinaryDisplay::Raw
+        } else if self.hexdump || self.hexdump_all {
+            BinaryDisplay::Hexdump
+        } else if self.cbor {
+            BinaryDisplay::Cbor
+        } else if self.smart_display && std::str::from_utf8(data).is_ok() {
+            // Lossless for valid UTF-8
+            BinaryDisplay::Text
+        } else if self.smart_display && self.binary_display == BinaryDisplay::Text {
+            BinaryDisplay::Hexdump
+        } else {
+            self.binary_display
+        }
+    }
+}
+
+// --auto-pong-data
+#[derive(Clone, Debug)]
+enum PongData {
+    Echo,
+    Empty,
+    Custom(Vec<u8>),
+}
+
 // Custom verifier for --no-check (skip server certificate validation)
 #[derive(Debug)]
 struct NoVerifier;
@@ -88,100 +1117,1918 @@ impl ServerCertVerifier for NoVerifier {
     }
 }
 
-// Entry: parse args; show help when --connect is missing
-#[tokio::main]
-async fn main() {
-    let opts = Opts::parse();
+// Verifier for --pin-sha256: trust only a leaf certificate with this SHA-256 (chain and name
+// are not checked), but still verify handshake signatures so the server must hold the key
+#[derive(Debug)]
+struct PinVerifier {
+    pin: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
 
-    if opts.connect.is_none() {
-        let mut cmd = Opts::command();
-        cmd.print_help().ok();
-        println!();
-        return;
+impl PinVerifier {
+    fn new(pin: &[u8; 32]) -> Self {
+        Self {
+            pin: *pin,
+            algorithms: rustls::crypto::aws_lc_rs::default_provider()
+                .signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = Sha256::digest(end_entity.as_ref());
+        if digest.as_slice() != self.pin {
+            return Err(rustls::Error::General(format!(
+                "certificate pin mismatch (server sha256: {})",
+                BASE64_STANDARD.encode(digest)
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// Verifier for --verify-depth: reject chains with more intermediates than allowed, then
+// delegate to the regular WebPKI verification
+#[derive(Debug)]
+struct DepthLimitVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    max_intermediates: usize,
+}
+
+impl ServerCertVerifier for DepthLimitVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if intermediates.len() > self.max_intermediates {
+            return Err(rustls::Error::General(format!(
+                "certificate chain has {} intermediates, --verify-depth allows {}",
+                intermediates.len(),
+                self.max_intermediates
+            )));
+        }
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// Verifier for --insecure-hosts: accept any certificate from the listed hosts and delegate
+// everything else to the regular verification. Handshake signatures are still checked
+#[derive(Debug)]
+struct InsecureHostsVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    hosts: Vec<String>,
+}
+
+impl ServerCertVerifier for InsecureHostsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let name = server_name.to_str();
+        if self
+            .hosts
+            .iter()
+            .any(|host| host.eq_ignore_ascii_case(&name))
+        {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// Parse a --bind value: "ip:port", "[v6]:port", or a bare IP (any port)
+fn parse_bind(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    value
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 0))
+        .map_err(|_| format!("expected IP or IP:PORT, got \"{value}\""))
+}
+
+// Parse a --resolve value: "host:ip", split on the first colon (an IPv6 address may be
+// bracketed)
+fn parse_resolve(value: &str) -> Result<(String, IpAddr), String> {
+    let Some((host, ip)) = value.split_once(':') else {
+        return Err(format!("expected HOST:IP, got \"{value}\""));
+    };
+    let ip = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map_err(|_| format!("invalid IP address in \"{value}\""))?;
+    Ok((host.to_ascii_lowercase(), ip))
+}
+
+// Parse --send-rate-ramp: "start:end:secs", rates in messages per second
+fn parse_ramp(value: &str) -> Result<Ramp, String> {
+    let usage = || format!("expected START:END:SECS with positive rates, got \"{value}\"");
+    let parts: Vec<&str> = value.split(':').collect();
+    let [start, end, secs] = parts[..] else {
+        return Err(usage());
+    };
+    let rate = |v: &str| v.parse::<f64>().ok().filter(|r| r.is_finite() && *r > 0.0);
+    let (Some(start), Some(end), Ok(secs)) = (rate(start), rate(end), secs.parse::<u64>()) else {
+        return Err(usage());
+    };
+    Ok(Ramp {
+        start,
+        end,
+        duration: Duration::from_secs(secs),
+    })
+}
+
+// Parse --deadline: an RFC 3339 timestamp with an offset ("Z" for UTC)
+fn parse_deadline(value: &str) -> Result<DateTime<FixedOffset>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map_err(|err| format!("{err} (expected e.g. 2024-06-01T12:00:00Z)"))
+}
+
+// Parse --every-jitter: a fraction of the interval, below 1 so no interval is zero
+fn parse_jitter(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(jitter) if (0.0..1.0).contains(&jitter) => Ok(jitter),
+        _ => Err(format!(
+            "expected a fraction from 0 up to (not including) 1, got \"{value}\""
+        )),
+    }
+}
+
+// --input-prefix-strip: only a match at the start of the line counts
+fn parse_prefix_strip(value: &str) -> Result<Regex, String> {
+    Regex::new(&format!("^(?:{value})")).map_err(|err| err.to_string())
+}
+
+// Parse --auto-pong-data; control frame payloads are limited to 125 bytes
+fn parse_pong_data(value: &str) -> Result<PongData, String> {
+    match value {
+        "echo" => Ok(PongData::Echo),
+        "empty" => Ok(PongData::Empty),
+        custom if custom.len() <= 125 => Ok(PongData::Custom(custom.as_bytes().to_vec())),
+        custom => Err(format!(
+            "pong data is {} bytes, control frames carry at most 125",
+            custom.len()
+        )),
+    }
+}
+
+fn parse_trace_header(value: &str) -> Result<TraceHeader, String> {
+    let (name, value) = match value.split_once(':') {
+        Some((name, value)) => (name.trim(), Some(value.trim())),
+        None => (value.trim(), None),
+    };
+    let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| err.to_string())?;
+    let value = value
+        .map(HeaderValue::from_str)
+        .transpose()
+        .map_err(|err| err.to_string())?;
+    Ok(TraceHeader { name, value })
+}
+
+fn parse_ping_payload(value: &str) -> Result<PingPayload, String> {
+    let data = match value.strip_prefix("hex:") {
+        Some(hex) => decode_hex(hex).ok_or_else(|| format!("invalid hex \"{hex}\""))?,
+        None => value.as_bytes().to_vec(),
+    };
+    if data.len() > 125 {
+        return Err(format!(
+            "ping payload is {} bytes, control frames carry at most 125",
+            data.len()
+        ));
+    }
+    Ok(PingPayload(data))
+}
+
+// A JSON Pointer is empty (the whole document) or starts with '/'
+fn parse_json_pointer(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.starts_with('/') {
+        Ok(value.to_string())
+    } else {
+        Err(format!("JSON Pointer must start with '/' (try /{value})"))
+    }
+}
+
+// Parse a --pin-sha256 value: base64 of the 32-byte digest, optionally prefixed with "sha256/"
+fn parse_pin(value: &str) -> Result<[u8; 32], String> {
+    let encoded = value.strip_prefix("sha256/").unwrap_or(value);
+    let pin = BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|err| format!("invalid base64: {err}"))?;
+    let len = pin.len();
+    pin.try_into()
+        .map_err(|_| format!("expected a 32-byte SHA-256 digest, got {len} bytes"))
+}
+
+// Parse --challenge-response: "hmac-sha256:" and the secret, the only scheme so far
+fn parse_challenge_response(value: &str) -> Result<String, String> {
+    let secret = value
+        .strip_prefix("hmac-sha256:")
+        .ok_or("expected hmac-sha256:<secret>")?;
+    if secret.is_empty() {
+        return Err("the secret is empty".into());
+    }
+    Ok(secret.to_string())
+}
+
+// HMAC-SHA256 (RFC 2104) on top of the sha2 crate already used for --pin-sha256
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(data)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+// Max incoming lines held back while /pause is active; the oldest are dropped beyond this
+const PAUSE_BUFFER_CAP: usize = 1000;
+
+// Per-connection state shared by the main loop, slash commands, and message handling
+#[derive(Default)]
+struct Session {
+    // Set by /pause: incoming lines are held back instead of printed
+    paused: bool,
+    held: VecDeque<String>,
+    dropped: usize,
+    // --buffer-incoming: held lines note their arrival, counted from the first one held
+    buffering: bool,
+    burst_start: Option<Instant>,
+    // --dedup: the last incoming payload line and how many times in a row it arrived
+    last_line: Option<String>,
+    repeats: usize,
+    // --assert-receive: whether an incoming text message contained the expected text
+    assert_received: bool,
+    // --error-on-match: the incoming text message that matched
+    error_match: Option<String>,
+    // Set by /connect: the URL to open once the current connection is closed
+    switch_to: Option<String>,
+    // --close-reason-file: sent with the close frame that ends the session
+    close_reason: Option<String>,
+    // --pretty-close: the code of the last close frame from the server
+    close_code: Option<u16>,
+    // /macro: recorded lines by name (sorted, for /macro list)
+    macros: BTreeMap<String, Vec<String>>,
+    // --output-split: number of the last file written
+    split_count: u64,
+    // --local-echo-format / --remote-echo-format, and the {n} each is at
+    local_format: String,
+    remote_format: String,
+    sent_count: u64,
+    received_count: u64,
+    // --challenge-response: the connection's first message hasn't been answered yet
+    challenge_pending: bool,
+    // --conversation: a line was sent and its reply hasn't arrived yet
+    awaiting_reply: bool,
+    // --number: the last sequence number printed, shared by both directions
+    number: bool,
+    line_number: u64,
+    // --raw-output: slash commands don't echo what they send either
+    raw_output: bool,
+}
+
+impl Session {
+    // The close frame that ends the session: code 1000 with the --close-reason-file reason, or
+    // no payload at all
+    fn close_message(&self) -> Message {
+        Message::Close(self.close_reason.as_ref().map(|reason| CloseFrame {
+            code: CloseCode::Normal,
+            reason: reason.clone().into(),
+        }))
+    }
+
+    // Print an incoming line, or hold it back while paused
+    fn show(&mut self, line: String) {
+        self.end_repeats();
+        let line = self.numbered(line);
+        self.hold_or_print(line);
+    }
+
+    // Print an outgoing echo line (never held back by /pause)
+    fn echo(&mut self, line: &str) {
+        self.end_repeats();
+        self.sent_count += 1;
+        let line = render_echo(&self.local_format, line, self.sent_count);
+        println!("{}", self.numbered(line));
+    }
+
+    // --number: "[n] " in front, numbered in the order lines are printed (or held by /pause)
+    fn numbered(&mut self, line: String) -> String {
+        if !self.number {
+            return line;
+        }
+        self.line_number += 1;
+        format!("[{}] {line}", self.line_number)
+    }
+
+    // Print an incoming text/binary payload through --remote-echo-format
+    fn show_incoming(&mut self, payload: &str, dedup: bool) {
+        self.received_count += 1;
+        let line = render_echo(&self.remote_format, payload, self.received_count);
+        self.show_payload(line, dedup);
+    }
+
+    // Print an incoming text/binary payload line; with --dedup, a repeat of the
+    // previous payload only bumps a counter
+    fn show_payload(&mut self, line: String, dedup: bool) {
+        if !dedup {
+            self.show(line);
+            return;
+        }
+        if self.last_line.as_ref() == Some(&line) {
+            self.repeats += 1;
+            if !self.paused && std::io::stdout().is_terminal() {
+                // Redraw the previous line in place with the new count
+                println!("\x1b[1A\x1b[2K{line} (x{})", self.repeats);
+            }
+            return;
+        }
+        self.show(line.clone());
+        self.last_line = Some(line);
+        self.repeats = 1;
+    }
+
+    // Close a run of repeats; if the count could not be redrawn in place, print a summary line
+    fn end_repeats(&mut self) {
+        if let Some(line) = self.last_line.take() {
+            if self.repeats > 1 && (self.paused || !std::io::stdout().is_terminal()) {
+                self.hold_or_print(format!("{line} (x{})", self.repeats));
+            }
+        }
+        self.repeats = 0;
+    }
+
+    fn hold_or_print(&mut self, line: String) {
+        if !self.paused {
+            println!("{line}");
+            return;
+        }
+        let line = if self.buffering {
+            let start = *self.burst_start.get_or_insert_with(Instant::now);
+            format!("[+{}ms] {line}", start.elapsed().as_millis())
+        } else {
+            line
+        };
+        if self.held.len() == PAUSE_BUFFER_CAP {
+            self.held.pop_front();
+            self.dropped += 1;
+        }
+        self.held.push_back(line);
+    }
+
+    // Leave pause mode and catch up on everything held back
+    fn resume(&mut self) {
+        self.paused = false;
+        self.flush();
+    }
+
+    // Print everything held back; --buffer-incoming stays on, starting a new burst
+    fn flush(&mut self) {
+        self.burst_start = None;
+        if self.dropped > 0 {
+            println!("({} older messages dropped while paused)", self.dropped);
+            self.dropped = 0;
+        }
+        for line in self.held.drain(..) {
+            println!("{line}");
+        }
+    }
+}
+
+// Bracketed paste markers the terminal wraps around pasted text
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+// Collects a bracketed paste spread over several stdin lines into one message
+#[derive(Default)]
+struct Paste {
+    enabled: bool,
+    block: Option<Vec<String>>,
+}
+
+impl Paste {
+    // Returns the next input to send (a typed line or a whole pasted block),
+    // or None while a paste is still arriving
+    fn feed(&mut self, line: String) -> Option<String> {
+        if !self.enabled {
+            return Some(line);
+        }
+        let started = line.contains(PASTE_START);
+        let ended = line.contains(PASTE_END);
+        let line = line.replace(PASTE_START, "").replace(PASTE_END, "");
+
+        if started && !ended && self.block.is_none() {
+            self.block = Some(vec![line]);
+            return None;
+        }
+        let Some(block) = &mut self.block else {
+            return Some(line);
+        };
+        // A paste ending in a newline puts the end marker alone on the next line
+        if !(ended && line.is_empty()) {
+            block.push(line);
+        }
+        if ended {
+            self.block.take().map(|block| block.join("\n"))
+        } else {
+            None
+        }
+    }
+}
+
+// Turns the terminal's bracketed paste mode on, and off again when dropped
+struct BracketedPasteMode;
+
+impl BracketedPasteMode {
+    fn enable(paste: &mut Paste) -> Self {
+        paste.enabled = true;
+        print!("\x1b[?2004h");
+        std::io::stdout().flush().ok();
+        BracketedPasteMode
+    }
+}
+
+impl Drop for BracketedPasteMode {
+    fn drop(&mut self) {
+        print!("\x1b[?2004l");
+        std::io::stdout().flush().ok();
+    }
+}
+
+// Terminal state while --char-mode is on: canonical mode and echo off, so reads return each
+// keystroke. Signals stay on, so Ctrl+C still ends the session. Restored on drop
+#[cfg(unix)]
+struct CharMode(libc::termios);
+
+#[cfg(unix)]
+impl CharMode {
+    fn enable() -> std::io::Result<Self> {
+        // SAFETY: termios is plain data that tcgetattr fills in completely
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: both calls only read or write the termios struct passed in
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let saved = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(CharMode(saved))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for CharMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings saved by enable()
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+// Exit codes other than the generic 1, so scripts can tell failures apart (2 is clap's usage error)
+const EXIT_TIMEOUT: i32 = 3;
+const EXIT_HANDSHAKE: i32 = 4;
+const EXIT_ASSERTION: i32 = 5;
+const EXIT_UNCLEAN_CLOSE: i32 = 6;
+const EXIT_ERROR_MATCH: i32 = 7;
+
+// --require-clean-close failure
+const UNCLEAN_CLOSE: &str = "connection ended without a closing handshake (abnormal closure, 1006)";
+
+// Error that ends the process with a specific exit code
+#[derive(Debug)]
+struct ExitError {
+    code: i32,
+    message: String,
+}
+
+impl ExitError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ExitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+// --client-id, set once at startup so every diagnostic can be attributed to its process
+static CLIENT_ID: OnceLock<String> = OnceLock::new();
+
+// Print a diagnostic line to stderr, prefixed with [id] under --client-id
+fn diag(line: &str) {
+    match CLIENT_ID.get() {
+        Some(id) => eprintln!("[{id}] {line}"),
+        None => eprintln!("{line}"),
+    }
+}
+
+// Overwrite the current stderr line in place (the --status line), prefixed like diag()
+fn redraw(line: &str) {
+    match CLIENT_ID.get() {
+        Some(id) => eprint!("\r\x1b[2K[{id}] {line}"),
+        None => eprint!("\r\x1b[2K{line}"),
+    }
+}
+
+// --fd: set once the inherited socket has been handed to a connection, which owns (and
+// closes) it from then on
+#[cfg(unix)]
+static FD_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// The --fd socket, for the first connection only. A redirect, the --allow-downgrade retry or
+// /connect would need a second one, and the number may name an unrelated file by then
+#[cfg(unix)]
+fn take_inherited_fd(fd: i32) -> Result<i32, String> {
+    if FD_TAKEN.swap(true, Ordering::SeqCst) {
+        return Err(format!(
+            "--fd {fd} was used by the first connection and can't be reused for another one"
+        ));
+    }
+    Ok(fd)
+}
+
+// --no-warnings, set once at startup
+static NO_WARNINGS: AtomicBool = AtomicBool::new(false);
+
+// Print an advisory "warning: ..." line, unless --no-warnings; errors go through diag() as is
+fn warn(message: &str) {
+    if !NO_WARNINGS.load(Ordering::Relaxed) {
+        diag(&format!("warning: {message}"));
+    }
+}
+
+// Entry: parse args; show help when neither --connect nor a server mode is given
+#[tokio::main]
+async fn main() {
+    let mut opts = Opts::parse();
+    if opts.resilient {
+        opts.apply_resilient();
+    }
+
+    if opts.connect.is_none() && opts.echo_server.is_none() && opts.listen.is_none() {
+        let mut cmd = Opts::command();
+        cmd.print_help().ok();
+        println!();
+        return;
+    }
+    if let Some(id) = &opts.client_id {
+        CLIENT_ID.set(id.clone()).ok();
+    }
+    NO_WARNINGS.store(opts.no_warnings, Ordering::Relaxed);
+
+    let result = if let Some(port) = opts.echo_server {
+        server::echo_server(port, opts.once).await
+    } else if let Some(port) = opts.listen {
+        listen(port, &opts).await
+    } else if opts.role != Role::Client {
+        run_role(opts).await
+    } else {
+        run(opts).await
+    };
+    if let Err(err) = result {
+        diag(&format!("error: {err}"));
+        let code = err.downcast_ref::<ExitError>().map_or(1, |e| e.code);
+        std::process::exit(code);
+    }
+
+    // Exit right away: the runtime would otherwise wait on the blocking stdin read
+    std::process::exit(0);
+}
+
+// --listen, or --role server on the --connect URL's port; --cert makes it wss://
+async fn listen(port: u16, opts: &Opts) -> Result<(), Box<dyn std::error::Error>> {
+    let tls = opts.cert.as_deref().map(server_tls).transpose()?;
+    server::listen(port, opts.broadcast, tls, opts.wait_for_open).await
+}
+
+// How long --role auto waits for the TCP connect before settling on the server role
+const ROLE_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// --role server / auto: the URL names the port to listen on; auto reports which role it took
+async fn run_role(opts: Opts) -> Result<(), Box<dyn std::error::Error>> {
+    let url = normalize_url(opts.connect.as_deref().unwrap());
+    let uri: http::Uri = url.parse()?;
+    let default_port = if uri.scheme_str() == Some("wss") {
+        443
+    } else {
+        80
+    };
+    let port = uri.port_u16().unwrap_or(default_port);
+    let role = match opts.role {
+        Role::Auto => {
+            let host = uri.host().unwrap_or("localhost");
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let probe = TcpStream::connect((host, port));
+            match tokio::time::timeout(ROLE_PROBE_TIMEOUT, probe).await {
+                Ok(Ok(_)) => {
+                    diag("Role: client");
+                    Role::Client
+                }
+                Ok(Err(err)) => {
+                    diag(&format!("Role: server ({host}:{port}: {err})"));
+                    Role::Server
+                }
+                Err(_) => {
+                    diag(&format!("Role: server ({host}:{port}: connect timed out)"));
+                    Role::Server
+                }
+            }
+        }
+        role => role,
+    };
+    if role == Role::Server {
+        // Serving plain ws:// on a wss:// URL would leave every client failing its TLS handshake
+        if uri.scheme_str() == Some("wss") && opts.cert.is_none() {
+            Opts::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--role server with a wss:// URL requires --cert",
+                )
+                .exit();
+        }
+        listen(port, &opts).await
+    } else {
+        run(opts).await
+    }
+}
+
+// --resilient defaults
+const RESILIENT_REDIRECTS: u32 = 5;
+const RESILIENT_RECONNECT_MAX: u32 = 3;
+const RESILIENT_CONNECT_TIMEOUT: u64 = 10;
+
+// Connect and enter the interactive loop
+async fn run(opts: Opts) -> Result<(), Box<dyn std::error::Error>> {
+    let mut connect_url = normalize_url(opts.connect.as_deref().unwrap());
+    if opts.no_mask {
+        warn("--no-mask sends unmasked client frames, violating RFC 6455; most servers will close the connection (1002)");
+    }
+    if opts.handshake_only {
+        return handshake_probe(&connect_url, &opts).await;
+    }
+
+    // --status-line: counters fed by the connection's Wire and message sink
+    if opts.status_line || opts.stats || opts.compression_stats || opts.summary_on_signal {
+        TRAFFIC.get_or_init(Traffic::default);
+    }
+    let renderer = match TRAFFIC.get() {
+        Some(traffic) if opts.status_line => status::spawn_renderer(traffic),
+        _ => None,
+    };
+    let mut summary_signal = SummarySignal::new(opts.summary_on_signal)?;
+
+    if let Some(dir) = &opts.output_split {
+        fs::create_dir_all(dir).map_err(|err| format!("{}: {err}", dir.display()))?;
+    }
+
+    let line_limit = opts.max_line_length.map(|max| LineLimit {
+        max: usize::try_from(max).unwrap_or(usize::MAX),
+        truncate: opts.truncate_input,
+    });
+    let pacer = opts.timestamp_field.clone().map(Pacer::new);
+    let passes = if opts.replay_loop {
+        None
+    } else {
+        Some(opts.replay_loops.unwrap_or(1))
+    };
+    // --conversation is read like --input, one line per reply
+    let input_path = opts.input.clone().or_else(|| opts.conversation.clone());
+    let mut lines = input::spawn(
+        input_path.clone(),
+        passes,
+        opts.char_mode,
+        line_limit,
+        pacer,
+    );
+    let mut session = Session {
+        close_reason: opts
+            .close_reason_file
+            .as_deref()
+            .map(read_close_reason)
+            .transpose()?,
+        local_format: opts.local_echo_format.clone(),
+        remote_format: opts.remote_echo_format.clone(),
+        number: opts.number,
+        raw_output: opts.raw_output,
+        paused: opts.buffer_incoming,
+        buffering: opts.buffer_incoming,
+        ..Session::default()
+    };
+    let mut paste = Paste::default();
+    let mut reconnect_cap = ReconnectCap::new(opts.max_reconnects_per_minute);
+    // Bracketed paste only on an interactive terminal; piped input stays one message per line
+    let _paste_mode = (!opts.char_mode
+        && input_path.is_none()
+        && std::io::stdin().is_terminal()
+        && std::io::stdout().is_terminal())
+    .then(|| BracketedPasteMode::enable(&mut paste));
+    // --char-mode: keystrokes reach us unbuffered only with the terminal's line editing off
+    #[cfg(unix)]
+    let _char_mode = (opts.char_mode && input_path.is_none() && std::io::stdin().is_terminal())
+        .then(CharMode::enable)
+        .transpose()?;
+
+    // --timeout: hard deadline for the whole session, regardless of traffic
+    let session_deadline = tokio::time::sleep(Duration::from_secs(opts.timeout.unwrap_or(0)));
+    tokio::pin!(session_deadline);
+    let mut timed_out = false;
+
+    // --conversation-timeout: the wait for the reply to the last line sent
+    let reply_window = Duration::from_millis(opts.conversation_timeout.unwrap_or(0));
+    let reply_timer = tokio::time::sleep(reply_window);
+    tokio::pin!(reply_timer);
+    let mut reply_timed_out = false;
+
+    // --deadline: the same, at a wall-clock time; ends the session normally
+    let until_deadline = match opts.deadline {
+        Some(deadline) => (deadline.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .map_err(|_| format!("--deadline {} is in the past", deadline.to_rfc3339()))?,
+        None => Duration::ZERO,
+    };
+    let deadline_timer = tokio::time::sleep(until_deadline);
+    tokio::pin!(deadline_timer);
+    let mut deadline_reached = false;
+
+    // --require-clean-close: whether the last connection ended with a close frame from the server
+    let mut clean_close;
+
+    // --drain: pushed back by every incoming message, so it fires once the feed goes quiet
+    let drain_window = Duration::from_millis(opts.drain.unwrap_or(0));
+    let drain_timer = tokio::time::sleep(drain_window);
+    tokio::pin!(drain_timer);
+
+    // --reconnect-on-idle: same idea, but the quiet connection is assumed stale and replaced
+    let idle_window = Duration::from_secs(opts.reconnect_on_idle.unwrap_or(0));
+    let idle_timer = tokio::time::sleep(idle_window);
+    tokio::pin!(idle_timer);
+
+    // --every / --send-rate-ramp: the next scheduled message, rescheduled after each send
+    let mut schedule = Schedule::new(
+        Duration::from_millis(opts.every.unwrap_or(0)),
+        opts.send_rate_ramp,
+        opts.every_jitter,
+    );
+    let every_timer = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(every_timer);
+
+    // --ping-interval: the next keep-alive ping, counted from the last one
+    let ping_window = Duration::from_secs(opts.ping_interval.unwrap_or(0));
+    let ping_timer = tokio::time::sleep(ping_window);
+    tokio::pin!(ping_timer);
+
+    // --inactivity-ping: pushed back by every message either way, so it fires once both go quiet
+    let quiet_window = Duration::from_secs(opts.inactivity_ping.unwrap_or(0));
+    let quiet_timer = tokio::time::sleep(quiet_window);
+    tokio::pin!(quiet_timer);
+
+    // --replay-unsent: the line whose send hadn't completed when the connection dropped
+    let mut unsent: Option<Message> = None;
+
+    // --on-reconnect: set when the next connection is a reconnect, and how many there were
+    let mut reconnecting = false;
+    let mut reconnects = 0;
+    // --template: sends still to go, carried over to the next connection if this one drops
+    let mut template = opts.template.clone().map(Template::new);
+    let mut template_sent = 0;
+
+    // One iteration per connection; /connect ends the current one and names the next,
+    // --reconnect / --reconnect-on-idle end it and reuse the same URL
+    loop {
+        let started = Instant::now();
+        let ws_stream = if opts.reconnect {
+            connect_with_retries(&connect_url, &opts, &mut reconnect_cap).await?
+        } else {
+            connect_with_fallback(&connect_url, &opts)
+                .await
+                .map_err(upgrade_rejected)?
+        };
+        if opts.print_handshake_latency {
+            print_latency(started);
+        }
+        // --raw-output keeps stdout binary-clean: no banner, no echo of sent lines
+        if !opts.raw_output {
+            println!("Connected (press CTRL+C to quit)");
+        }
+        if std::mem::take(&mut reconnecting) {
+            reconnects += 1;
+            if let Some(command) = &opts.on_reconnect {
+                on_reconnect(command, reconnects, opts.on_reconnect_fatal).await?;
+            }
+        }
+        // Give a slow server a moment before the first line goes out (input waits meanwhile)
+        if let Some(ms) = opts.after_connect_delay {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+        }
+
+        let (write, mut read) = ws_stream.split();
+        let mut write = Metered::new(write, TRAFFIC.get(), !opts.no_mask);
+        if let Some(traffic) = TRAFFIC.get() {
+            traffic.reset();
+        }
+        drain_timer
+            .as_mut()
+            .reset(tokio::time::Instant::now() + drain_window);
+        idle_timer
+            .as_mut()
+            .reset(tokio::time::Instant::now() + idle_window);
+        every_timer
+            .as_mut()
+            .reset(tokio::time::Instant::now() + schedule.next_interval());
+        ping_timer
+            .as_mut()
+            .reset(tokio::time::Instant::now() + ping_window);
+        quiet_timer
+            .as_mut()
+            .reset(tokio::time::Instant::now() + quiet_window);
+        // Set when the server (not the user) ended the connection, or it went idle
+        let mut dropped = false;
+        let mut idle = false;
+        let mut interrupted = false;
+        let mut input_ended = false;
+        clean_close = false;
+        // Lines fed to the connection but not flushed yet (--max-queued-sends)
+        let mut queued = 0;
+        session.challenge_pending = opts.challenge_response.is_some();
+        session.awaiting_reply = false;
+
+        if let Some(message) = unsent.clone() {
+            diag("Replaying 1 unsent message");
+            match write.send(message).await {
+                Ok(()) => unsent = None,
+                Err(err) => {
+                    diag(&format!("error: {err}"));
+                    dropped = true;
+                }
+            }
+        }
+        if let Some(template) = &mut template {
+            while !dropped && template_sent < opts.repeat {
+                let text = template.render(template_sent + 1);
+                if !opts.raw_output {
+                    session.echo(&text);
+                }
+                if let Err(err) = write.send(Message::Text(text)).await {
+                    if !opts.reconnect {
+                        return Err(err.into());
+                    }
+                    diag(&format!("error: {err}"));
+                    dropped = true;
+                } else {
+                    template_sent += 1;
+                }
+            }
+        }
+
+        // Handle input lines, server messages, and Ctrl+C concurrently
+        while !dropped {
+            tokio::select! {
+                // --max-queued-sends: stop reading input while the queue is full; --challenge-response:
+                // hold it until the challenge is answered; --conversation: until the reply is in
+                line = lines.recv(), if opts.max_queued_sends.is_none_or(|max| queued < max)
+                    && !session.challenge_pending
+                    && !session.awaiting_reply => {
+                    match line.transpose() {
+                        Ok(Some(line)) => {
+                            let Some(line) = paste.feed(line) else {
+                                continue;
+                            };
+                            if opts.buffer_incoming && line.is_empty() {
+                                session.flush();
+                                continue;
+                            }
+                            if opts.slash && line.starts_with('/') {
+                                if handle_slash_command(&line, &mut write, &mut session).await? {
+                                    break;
+                                }
+                            } else {
+                                let line = match &opts.input_prefix_strip {
+                                    Some(re) => re.replace(&line, "").into_owned(),
+                                    None => line,
+                                };
+                                let line = format!(
+                                    "{}{line}{}",
+                                    opts.message_prefix.as_deref().unwrap_or(""),
+                                    opts.message_suffix.as_deref().unwrap_or("")
+                                );
+                                let line = if opts.input_json_lines {
+                                    match serde_json::from_str::<serde_json::Value>(&line) {
+                                        Ok(value) => value.to_string(),
+                                        Err(err) if opts.strict_json => {
+                                            return Err(format!("invalid JSON input: {err}").into());
+                                        }
+                                        Err(err) => {
+                                            diag(&format!("error: invalid JSON, not sent: {err}"));
+                                            continue;
+                                        }
+                                    }
+                                } else {
+                                    line
+                                };
+                                // --char-mode: the terminal no longer echoes; a per-keystroke
+                                // "> " line would be noise, so the server's echo is all there is
+                                if !opts.raw_output && !opts.char_mode {
+                                    session.echo(&line);
+                                }
+                                let message = Message::Text(line);
+                                unsent = opts.replay_unsent.then(|| message.clone());
+                                let sent = if opts.max_queued_sends.is_some() {
+                                    // Queue without waiting for the flush; the branch below drains it
+                                    queued += 1;
+                                    write.feed(message).await
+                                } else {
+                                    write.send(message).await
+                                };
+                                if let Err(err) = sent {
+                                    if !opts.reconnect {
+                                        return Err(err.into());
+                                    }
+                                    diag(&format!("error: {err}"));
+                                    dropped = true;
+                                    break;
+                                }
+                                unsent = None;
+                                quiet_timer
+                                    .as_mut()
+                                    .reset(tokio::time::Instant::now() + quiet_window);
+                                if opts.conversation.is_some() {
+                                    session.awaiting_reply = true;
+                                    reply_timer
+                                        .as_mut()
+                                        .reset(tokio::time::Instant::now() + reply_window);
+                                }
+                            }
+                        }
+                        Ok(None) => {
+                            if queued > 0 {
+                                write.flush().await?;
+                            }
+                            write.send(session.close_message()).await.ok();
+                            input_ended = true;
+                            break;
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                flushed = write.flush(), if queued > 0 => {
+                    if let Err(err) = flushed {
+                        if !opts.reconnect {
+                            return Err(err.into());
+                        }
+                        diag(&format!("error: {err}"));
+                        dropped = true;
+                        break;
+                    }
+                    queued = 0;
+                }
+                msg = read.next() => {
+                    match msg {
+                        Some(Ok(message)) => {
+                            let server_close = matches!(message, Message::Close(_));
+                            if let Some(traffic) = TRAFFIC.get() {
+                                if message.is_text() || message.is_binary() {
+                                    traffic.add_message_received(message.len());
+                                }
+                            }
+                            if handle_message(message, &mut write, &opts, &mut session).await? {
+                                dropped = server_close;
+                                clean_close = server_close;
+                                break;
+                            }
+                            let now = tokio::time::Instant::now();
+                            drain_timer.as_mut().reset(now + drain_window);
+                            idle_timer.as_mut().reset(now + idle_window);
+                            quiet_timer.as_mut().reset(now + quiet_window);
+                        }
+                        Some(Err(err)) if opts.reconnect => {
+                            diag(&format!("error: {err}"));
+                            dropped = true;
+                            break;
+                        }
+                        Some(Err(err)) if opts.require_clean_close => {
+                            let message = format!("{UNCLEAN_CLOSE}: {err}");
+                            return Err(ExitError::new(EXIT_UNCLEAN_CLOSE, message).into());
+                        }
+                        Some(Err(err)) => return Err(err.into()),
+                        None => {
+                            dropped = true;
+                            break;
+                        }
+                    }
+                }
+                _ = &mut every_timer, if opts.every_message.is_some() => {
+                    let text = opts.every_message.clone().unwrap_or_default();
+                    if !opts.raw_output {
+                        session.echo(&text);
+                    }
+                    if let Err(err) = write.send(Message::Text(text)).await {
+                        if !opts.reconnect {
+                            return Err(err.into());
+                        }
+                        diag(&format!("error: {err}"));
+                        dropped = true;
+                        break;
+                    }
+                    // From the missed deadline, not from now, so sends don't drift
+                    let next = every_timer.deadline() + schedule.next_interval();
+                    every_timer.as_mut().reset(next);
+                    quiet_timer
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + quiet_window);
+                }
+                _ = &mut ping_timer, if opts.ping_interval.is_some() => {
+                    let data = opts.ping_payload.clone().map_or_else(Vec::new, |p| p.0);
+                    if let Err(err) = write.send(Message::Ping(data)).await {
+                        if !opts.reconnect {
+                            return Err(err.into());
+                        }
+                        diag(&format!("error: {err}"));
+                        dropped = true;
+                        break;
+                    }
+                    let next = ping_timer.deadline() + ping_window;
+                    ping_timer.as_mut().reset(next);
+                }
+                _ = &mut quiet_timer, if opts.inactivity_ping.is_some() => {
+                    let data = opts.ping_payload.clone().map_or_else(Vec::new, |p| p.0);
+                    if let Err(err) = write.send(Message::Ping(data)).await {
+                        if !opts.reconnect {
+                            return Err(err.into());
+                        }
+                        diag(&format!("error: {err}"));
+                        dropped = true;
+                        break;
+                    }
+                    // Still quiet: ping again after another full window
+                    quiet_timer
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + quiet_window);
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    write.send(session.close_message()).await.ok();
+                    interrupted = true;
+                    break;
+                }
+                _ = summary_signal.recv() => {
+                    if let Some(traffic) = TRAFFIC.get() {
+                        diag(&format!("Stats: {}", traffic.stats(opts.count_frames)));
+                    }
+                }
+                _ = &mut session_deadline, if opts.timeout.is_some() => {
+                    write.send(session.close_message()).await.ok();
+                    timed_out = true;
+                    break;
+                }
+                _ = &mut reply_timer, if session.awaiting_reply
+                    && opts.conversation_timeout.is_some() => {
+                    write.send(session.close_message()).await.ok();
+                    reply_timed_out = true;
+                    break;
+                }
+                _ = &mut deadline_timer, if opts.deadline.is_some() => {
+                    diag("Deadline reached, closing");
+                    write.send(session.close_message()).await.ok();
+                    deadline_reached = true;
+                    break;
+                }
+                _ = &mut drain_timer, if opts.drain.is_some() => {
+                    write.send(session.close_message()).await.ok();
+                    break;
+                }
+                _ = &mut idle_timer, if opts.reconnect_on_idle.is_some() => {
+                    write.send(Message::Close(None)).await.ok();
+                    idle = true;
+                    break;
+                }
+            }
+        }
+
+        // Every other way out of the loop above sent a close frame
+        if !dropped {
+            // CTRL+C or end of input: what the server sent before it saw the close is still printed
+            let show = (interrupted || input_ended).then_some((&opts, &mut session));
+            clean_close =
+                await_close(&mut read, Duration::from_millis(opts.close_timeout), show).await;
+        }
+
+        if timed_out || deadline_reached {
+            break;
+        }
+        if let Some(url) = session.switch_to.take() {
+            connect_url = normalize_url(&url);
+            diag(&format!("Switching to {connect_url}"));
+        } else if idle {
+            let secs = idle_window.as_secs();
+            diag(&format!(
+                "No message for {secs}s, reconnecting to {connect_url}"
+            ));
+            reconnecting = true;
+        } else if dropped && opts.reconnect {
+            // Pause so a server that keeps closing isn't hammered
+            diag(&format!(
+                "Connection lost, reconnecting to {connect_url} in {}s",
+                opts.reconnect_delay
+            ));
+            tokio::time::sleep(Duration::from_secs(opts.reconnect_delay).max(RECONNECT_MIN_DELAY))
+                .await;
+            reconnecting = true;
+        } else {
+            break;
+        }
+    }
+
+    // Print the pending --dedup summary, if any
+    session.end_repeats();
+    // --buffer-incoming: whatever is still held would be lost
+    if opts.buffer_incoming {
+        session.flush();
+    }
+
+    if let Some(traffic) = TRAFFIC.get() {
+        match renderer {
+            Some(renderer) => {
+                renderer.abort();
+                eprint!("\r\x1b[2K");
+            }
+            None if opts.status_line => diag(&format!("Traffic: {}", traffic.summary())),
+            None => {}
+        }
+        if opts.stats {
+            diag(&format!("Stats: {}", traffic.stats(opts.count_frames)));
+        }
+        if opts.compression_stats {
+            diag(&format!("Compression: {}", traffic.compression()));
+        }
+    }
+
+    if opts.pretty_close {
+        if let Some(code) = session.close_code {
+            diag(&format!("Close: {code} {}", close_code_explanation(code)));
+        }
+    }
+
+    // --error-on-match wins over everything else: the server reported a failure
+    if let Some(text) = &session.error_match {
+        let message = format!("server sent an error message: {text}");
+        return Err(ExitError::new(EXIT_ERROR_MATCH, message).into());
+    }
+
+    if reply_timed_out {
+        let ms = opts.conversation_timeout.unwrap_or(0);
+        let message = format!("no reply within {ms}ms (--conversation-timeout)");
+        return Err(ExitError::new(EXIT_TIMEOUT, message).into());
+    }
+
+    if opts.require_clean_close && !clean_close {
+        return Err(ExitError::new(EXIT_UNCLEAN_CLOSE, UNCLEAN_CLOSE).into());
+    }
+
+    // --assert-receive decides the outcome on its own; --timeout then only bounds the wait
+    if let Some(expected) = &opts.assert_receive {
+        if !session.assert_received {
+            let message = format!("assertion failed: no incoming message contained \"{expected}\"");
+            return Err(ExitError::new(EXIT_ASSERTION, message).into());
+        }
+        return Ok(());
+    }
+
+    if timed_out {
+        let secs = opts.timeout.unwrap_or(0);
+        return Err(
+            ExitError::new(EXIT_TIMEOUT, format!("session timed out after {secs}s")).into(),
+        );
+    }
+
+    Ok(())
+}
+
+// A close frame holds a 2-byte code and at most 123 bytes of reason (RFC 6455 section 5.5)
+const CLOSE_REASON_MAX: usize = 123;
+
+// Read --close-reason-file. The reason must be UTF-8; a longer one is cut at the last
+// character boundary that fits
+fn read_close_reason(path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    let data = fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let mut reason = String::from_utf8(data)
+        .map_err(|_| format!("{}: close reason must be UTF-8 text", path.display()))?;
+    if reason.len() > CLOSE_REASON_MAX {
+        let mut end = CLOSE_REASON_MAX;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        warn(&format!(
+            "close reason is {} bytes, truncated to {end}",
+            reason.len()
+        ));
+        reason.truncate(end);
+    }
+    Ok(reason)
+}
+
+// --handshake-only: the upgrade succeeding (details printed by connect()) is the whole check.
+// Close with 1000 right away; stdin is never touched
+async fn handshake_probe(connect_url: &str, opts: &Opts) -> Result<(), Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let ws_stream = connect_with_fallback(connect_url, opts)
+        .await
+        .map_err(upgrade_rejected)?;
+    if opts.print_handshake_latency {
+        print_latency(started);
+    }
+    let (mut write, mut read) = ws_stream.split();
+    write
+        .send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "".into(),
+        })))
+        .await?;
+    await_close(&mut read, Duration::from_millis(opts.close_timeout), None).await;
+    Ok(())
+}
+
+// --print-handshake-latency: retries, redirects and fallbacks included, as that is what the
+// user waited for
+fn print_latency(started: Instant) {
+    diag(&format!("handshake: {}ms", started.elapsed().as_millis()));
+}
+
+// Complete the closing handshake: wait for the server's close frame (or the end of the stream),
+// for at most `timeout`. Messages still arriving meanwhile are dropped, or with `show` handled
+// like any other (replies to them go nowhere, the close is already out). True if the close came
+async fn await_close(
+    read: &mut SplitStream<WsStream>,
+    timeout: Duration,
+    mut show: Option<(&Opts, &mut Session)>,
+) -> bool {
+    let closed = tokio::time::timeout(timeout, async {
+        while let Some(Ok(message)) = read.next().await {
+            if message.is_close() {
+                return true;
+            }
+            if let Some((opts, session)) = show.as_mut() {
+                if let Some(traffic) = TRAFFIC.get() {
+                    if message.is_text() || message.is_binary() {
+                        traffic.add_message_received(message.len());
+                    }
+                }
+                let mut discard = futures_util::sink::drain().sink_map_err(|never| match never {});
+                handle_message(message, &mut discard, opts, session)
+                    .await
+                    .ok();
+            }
+        }
+        false
+    })
+    .await;
+    closed.unwrap_or_else(|_| {
+        diag(&format!(
+            "No close from the server within {}ms, dropping the connection",
+            timeout.as_millis()
+        ));
+        false
+    })
+}
+
+// Match wscat: default to ws:// when scheme is missing
+fn normalize_url(url: &str) -> String {
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("ws://{url}")
+    }
+}
+
+// --max-reconnects-per-minute: the times of the last N reconnect attempts, oldest first
+struct ReconnectCap {
+    max: Option<usize>,
+    attempts: VecDeque<tokio::time::Instant>,
+    // The session's first connect is not a reconnect
+    connected_once: bool,
+}
+
+impl ReconnectCap {
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn new(max: Option<u32>) -> Self {
+        Self {
+            max: max.map(|max| max as usize),
+            attempts: VecDeque::new(),
+            connected_once: false,
+        }
+    }
+
+    // Wait until one more attempt fits in the rolling window, then record it
+    async fn wait(&mut self) {
+        if !std::mem::replace(&mut self.connected_once, true) {
+            return;
+        }
+        let Some(max) = self.max else {
+            return;
+        };
+        if self.attempts.len() == max {
+            let ready = self.attempts.pop_front().unwrap() + Self::WINDOW;
+            let now = tokio::time::Instant::now();
+            if ready > now {
+                diag(&format!(
+                    "Reconnect rate cap reached ({max} per minute), waiting {}s",
+                    (ready - now).as_secs_f64().ceil()
+                ));
+                tokio::time::sleep_until(ready).await;
+            }
+        }
+        self.attempts.push_back(tokio::time::Instant::now());
+    }
+}
+
+// Floor for the reconnect wait, so --reconnect-delay 0 doesn't retry in a tight loop
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+
+// --reconnect: connect_with_fallback() until it succeeds or --reconnect-max attempts failed.
+// On a terminal the attempts share one status line, otherwise each gets its own
+async fn connect_with_retries(
+    connect_url: &str,
+    opts: &Opts,
+    cap: &mut ReconnectCap,
+) -> Result<WsStream, Box<dyn std::error::Error>> {
+    let tty = std::io::stderr().is_terminal();
+    let base = Duration::from_secs(opts.reconnect_delay);
+    let max = Duration::from_secs(opts.reconnect_max_delay);
+    let mut attempt = 0;
+    loop {
+        cap.wait().await;
+        let err = match connect_with_fallback(connect_url, opts).await {
+            Ok(ws_stream) => {
+                if tty && attempt > 0 {
+                    eprint!("\r\x1b[2K");
+                }
+                return Ok(ws_stream);
+            }
+            // Exit-code failures (e.g. --require-subprotocol) won't change on retry
+            Err(err) if err.is::<ExitError>() => return Err(err),
+            Err(err) => err,
+        };
+        if opts.reconnect_max.is_some_and(|max| attempt >= max) {
+            if tty {
+                eprintln!();
+            }
+            return Err(format!("giving up after {attempt} reconnect attempts: {err}").into());
+        }
+
+        attempt += 1;
+        let delay = opts
+            .reconnect_backoff
+            .delay(base, attempt, max)
+            .max(RECONNECT_MIN_DELAY);
+        if tty {
+            // Count down in place, one redraw per second
+            let prefix = CLIENT_ID
+                .get()
+                .map(|id| format!("[{id}] "))
+                .unwrap_or_default();
+            for left in (1..=delay.as_secs()).rev() {
+                eprint!(
+                    "\r\x1b[2K{prefix}reconnecting... attempt {attempt}, next in {left}s ({err})"
+                );
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            // Only whole seconds are counted down; the minimum delay is less than one
+            tokio::time::sleep(Duration::from_nanos(delay.subsec_nanos().into())).await;
+        } else {
+            let next = match delay.as_secs() {
+                0 => format!("{}ms", delay.as_millis()),
+                secs => format!("{secs}s"),
+            };
+            diag(&format!(
+                "reconnecting... attempt {attempt}, next in {next} ({err})"
+            ));
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// Headers tungstenite writes itself from the typed map, and fails without: -H overrides their
+// value there, so raw header mode can't keep their casing or order
+fn is_upgrade_header(name: &http::HeaderName) -> bool {
+    [
+        http::header::HOST,
+        http::header::UPGRADE,
+        http::header::CONNECTION,
+    ]
+    .contains(name)
+        || name.as_str().starts_with("sec-websocket-")
+}
+
+// connect(), plus --print-url and the --allow-downgrade retry
+async fn connect_with_fallback(
+    connect_url: &str,
+    opts: &Opts,
+) -> Result<WsStream, Box<dyn std::error::Error>> {
+    let mut url = connect_url.to_string();
+    let mut redirects = 0;
+    loop {
+        if opts.print_url {
+            diag(&format!("Connecting to {url}"));
+        }
+
+        let result = match connect_with_timeout(&url, opts).await {
+            // --allow-downgrade: a reset/garbled TLS handshake usually means the server speaks plain ws
+            Err(err)
+                if opts.allow_downgrade && url.starts_with("wss://") && is_tls_refusal(&*err) =>
+            {
+                let plain_url = url.replacen("wss://", "ws://", 1);
+                warn(&format!(
+                    "TLS handshake failed ({err}), retrying without TLS: {plain_url}"
+                ));
+                connect_with_timeout(&plain_url, opts).await
+            }
+            result => result,
+        };
+
+        // --max-redirects: a 3xx with a Location starts over at the new URL
+        let location = match &result {
+            Err(err) if redirects < opts.max_redirects.unwrap_or(0) => {
+                redirect_target(&**err, &url)
+            }
+            _ => None,
+        };
+        let Some(location) = location else {
+            return result;
+        };
+        redirects += 1;
+        diag(&format!("Redirected to {location}"));
+        url = location;
+    }
+}
+
+// An upgrade the server answered with another status (redirects already followed) exits 4,
+// like a subprotocol mismatch. Not under --reconnect, where it is retried like any failure
+fn upgrade_rejected(err: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+    match err.downcast_ref::<tokio_tungstenite::tungstenite::Error>() {
+        Some(tokio_tungstenite::tungstenite::Error::Http(_)) => {
+            ExitError::new(EXIT_HANDSHAKE, err.to_string()).into()
+        }
+        _ => err,
+    }
+}
+
+// connect(), bounded by --connect-timeout
+async fn connect_with_timeout(
+    connect_url: &str,
+    opts: &Opts,
+) -> Result<WsStream, Box<dyn std::error::Error>> {
+    let Some(secs) = opts.connect_timeout else {
+        return connect(connect_url, opts).await;
+    };
+    tokio::time::timeout(Duration::from_secs(secs), connect(connect_url, opts))
+        .await
+        .unwrap_or_else(|_| Err(format!("connection timed out after {secs}s").into()))
+}
+
+// Where a 3xx handshake response points, as a ws:// or wss:// URL. http(s) schemes are mapped
+// to their WebSocket counterparts, and a path-only Location stays on the current server
+fn redirect_target(err: &(dyn std::error::Error + 'static), url: &str) -> Option<String> {
+    let Some(tokio_tungstenite::tungstenite::Error::Http(response)) = err.downcast_ref() else {
+        return None;
+    };
+    if !response.status().is_redirection() {
+        return None;
+    }
+    let location = response
+        .headers()
+        .get(http::header::LOCATION)?
+        .to_str()
+        .ok()?;
+    if location.starts_with('/') {
+        let (scheme, rest) = url.split_once("://")?;
+        let authority = rest.split('/').next().unwrap_or(rest);
+        return Some(format!("{scheme}://{authority}{location}"));
+    }
+    let (scheme, rest) = location.split_once("://")?;
+    let scheme = match scheme.to_ascii_lowercase().as_str() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        _ => return None,
+    };
+    Some(format!("{scheme}://{rest}"))
+}
+
+// --status-line / --stats / --compression-stats counters; unset otherwise, so the connection
+// isn't instrumented
+static TRAFFIC: OnceLock<Traffic> = OnceLock::new();
+
+// TLS configs already built, by host, so reconnects don't re-read certificates from disk
+static TLS_CONFIGS: OnceLock<Mutex<HashMap<String, Arc<ClientConfig>>>> = OnceLock::new();
+
+// The TLS config for `host`: built on first use, or on every connect with --cert-reload
+// (picks up client certificates rotated on disk)
+fn tls_config_for(
+    host: &str,
+    opts: &Opts,
+) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error>> {
+    let cache = TLS_CONFIGS.get_or_init(Default::default);
+    if !opts.cert_reload {
+        if let Some(config) = cache.lock().unwrap().get(host) {
+            return Ok(config.clone());
+        }
+    }
+
+    let config = Arc::new(build_tls_config(
+        opts.cert.as_deref(),
+        opts.no_check,
+        opts.pin_sha256.as_ref(),
+        opts.verify_depth,
+        opts.trust_system_store,
+        &opts.insecure_hosts,
+        host,
+    )?);
+    if !opts.cert_reload {
+        cache
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), config.clone());
+    }
+    Ok(config)
+}
+
+// Build the upgrade request for `connect_url` (headers, TLS) and open the WebSocket
+async fn connect(connect_url: &str, opts: &Opts) -> Result<WsStream, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut request = connect_url.into_client_request()?;
+
+    // An explicit --origin wins over --auto-origin; -H Origin: (inserted below) wins over both
+    let origin = match &opts.origin {
+        Some(origin) => Some(origin.clone()),
+        None if opts.auto_origin => Some(origin_from_url(request.uri())?),
+        None => None,
+    };
+    if let Some(origin) = origin {
+        request
+            .headers_mut()
+            .insert(http::header::ORIGIN, HeaderValue::from_str(&origin)?);
+    }
+
+    if let Some(id) = &opts.client_id {
+        request
+            .headers_mut()
+            .insert("X-Client-Id", HeaderValue::from_str(id)?);
+    }
+
+    if let Some(trace) = &opts.trace_header {
+        let value = match &trace.value {
+            Some(value) => value.clone(),
+            None => HeaderValue::from_str(&template::random_uuid())?,
+        };
+        diag(&format!(
+            "{}: {}",
+            trace.name,
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+        request.headers_mut().insert(trace.name.clone(), value);
     }
 
-    if let Err(err) = run(opts).await {
-        eprintln!("error: {err}");
-        std::process::exit(1);
+    // Offered in preference order; the server picks one (or, non-compliantly, none)
+    if !opts.subprotocol.is_empty() {
+        request.headers_mut().insert(
+            http::header::SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_str(&opts.subprotocol.join(", "))?,
+        );
     }
-}
 
-// Connect and enter the interactive loop
-async fn run(opts: Opts) -> Result<(), Box<dyn std::error::Error>> {
-    let mut connect_url = opts.connect.unwrap();
-    if !connect_url.contains("://") {
-        // Match wscat: default to ws:// when scheme is missing
-        connect_url = format!("ws://{connect_url}");
+    // Overwrites the 13 into_client_request put there; generate_request writes whatever is set
+    if let Some(version) = opts.ws_version {
+        request.headers_mut().insert(
+            http::header::SEC_WEBSOCKET_VERSION,
+            HeaderValue::from(version),
+        );
     }
 
-    let mut request = connect_url.clone().into_client_request()?;
     // Parse repeatable -H/--header values
-    for header in opts.header {
-        let (name, value) = parse_header(&header)?;
-        request.headers_mut().insert(name, value);
+    let mut raw_headers = Vec::new();
+    for header in &opts.header {
+        let expanded;
+        let header = if opts.expand_placeholders {
+            expanded = expand_placeholders(header, request.uri());
+            &expanded
+        } else {
+            header
+        };
+        let (name, value) = parse_header(header)?;
+        if (opts.header_case_sensitive || opts.handshake_header_order) && !is_upgrade_header(&name)
+        {
+            // Keep the name as typed and the -H order; the typed header map would lowercase the
+            // name and group repeats together
+            request.headers_mut().remove(&name);
+            let name = if opts.header_case_sensitive {
+                header_name_as_typed(header).to_string()
+            } else {
+                name.to_string()
+            };
+            raw_headers.push((name, value));
+        } else {
+            request.headers_mut().insert(name, value);
+        }
     }
 
     // TLS config is only needed for wss
-    let connector = if connect_url.starts_with("wss://") {
-        Some(Connector::Rustls(Arc::new(build_tls_config(
-            opts.cert.as_deref(),
-            opts.no_check,
-        )?)))
+    let tls_config = if connect_url.starts_with("wss://") {
+        Some(tls_config_for(request.uri().host().unwrap_or(""), opts)?)
     } else {
         None
     };
 
-    let (ws_stream, _) = connect_async_tls_with_config(request, None, false, connector).await?;
-    println!("Connected (press CTRL+C to quit)");
+    #[cfg(unix)]
+    let fd = opts.fd.map(take_inherited_fd).transpose()?;
+    #[cfg(not(unix))]
+    let fd: Option<i32> = None;
 
-    let (mut write, mut read) = ws_stream.split();
-    let stdin = BufReader::new(tokio::io::stdin());
-    let mut lines = stdin.lines();
+    // Our own handshake is needed for raw header casing or order, for --slow-handshake, and
+    // whenever a subprotocol is offered: tungstenite fails outright when none comes back, while
+    // we only check it below
+    let manual_handshake =
+        !raw_headers.is_empty() || !opts.subprotocol.is_empty() || opts.slow_handshake.is_some();
 
-    // Handle stdin input, server messages, and Ctrl+C concurrently
-    loop {
-        tokio::select! {
-            line = lines.next_line() => {
-                match line {
-                    Ok(Some(line)) => {
-                        if opts.slash && line.starts_with('/') {
-                            if handle_slash_command(&line, &mut write).await? {
-                                break;
-                            }
-                        } else {
-                            println!("> {line}");
-                            write.send(Message::Text(line)).await?;
-                        }
-                    }
-                    Ok(None) => break,
-                    Err(err) => return Err(err.into()),
-                }
-            }
-            msg = read.next() => {
-                match msg {
-                    Some(Ok(message)) => {
-                        if handle_message(message, &mut write, opts.show_ping_pong).await? {
-                            break;
-                        }
-                    }
-                    Some(Err(err)) => return Err(err.into()),
-                    None => break,
-                }
-            }
-            _ = tokio::signal::ctrl_c() => {
-                write.send(Message::Close(None)).await.ok();
-                break;
+    // Open the socket ourselves: either adopt the inherited fd or connect explicitly
+    let tcp = match fd {
+        #[cfg(unix)]
+        Some(fd) => handshake::tcp_from_fd(fd)?,
+        _ => {
+            let host = request.uri().host().unwrap_or("");
+            let resolved = opts
+                .resolve
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(host))
+                .map(|(_, ip)| *ip);
+            handshake::connect_tcp(&request, resolved, opts.bind).await?
+        }
+    };
+    let stream = handshake::wrap_tls(tcp, &request, tls_config).await?;
+    if opts.print_cipher {
+        if let MaybeTlsStream::Rustls(tls) = &stream {
+            let (version, suite) = negotiated_tls(tls.get_ref().1);
+            diag(&format!("TLS: {version} {suite}"));
+        }
+    }
+    // --emit-connect-event: gathered now, while the TLS layer is still in reach
+    let (peer, tls) = match &stream {
+        MaybeTlsStream::Plain(tcp) => (tcp.peer_addr().ok(), None),
+        MaybeTlsStream::Rustls(tls) => {
+            let (tcp, conn) = tls.get_ref();
+            (tcp.peer_addr().ok(), Some(negotiated_tls(conn)))
+        }
+        _ => (None, None),
+    };
+    let stream = Wire::new(stream, opts.no_mask, opts.show_raw_handshake, TRAFFIC.get());
+
+    let result = if manual_handshake {
+        // Bypass tungstenite's handshake and write the request bytes ourselves
+        let byte_delay = Duration::from_millis(opts.slow_handshake.unwrap_or(0));
+        handshake::client_handshake(stream, request, &raw_headers, byte_delay).await
+    } else {
+        tokio_tungstenite::client_async(request, stream).await
+    };
+    // 426 lists the versions the server does speak
+    if let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = &result {
+        if response.status() == http::StatusCode::UPGRADE_REQUIRED {
+            if let Some(versions) = response.headers().get(http::header::SEC_WEBSOCKET_VERSION) {
+                diag(&format!(
+                    "Server supports Sec-WebSocket-Version: {}",
+                    String::from_utf8_lossy(versions.as_bytes())
+                ));
             }
         }
     }
+    let (ws_stream, response) = result?;
+
+    if !opts.subprotocol.is_empty() {
+        check_subprotocol(&response, &opts.subprotocol, opts.require_subprotocol)?;
+    }
+    print_response_headers(&response, &opts.print_response_header);
+    if opts.emit_connect_event {
+        let subprotocol = response
+            .headers()
+            .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+        let event = serde_json::json!({
+            "event": "connected",
+            "url": connect_url,
+            "subprotocol": subprotocol,
+            "tls_version": tls.as_ref().map(|(version, _)| version),
+            "tls_cipher": tls.as_ref().map(|(_, suite)| suite),
+            "peer": peer.map(|addr| addr.to_string()),
+            "handshake_ms": started.elapsed().as_millis() as u64,
+        });
+        println!("{event}");
+    }
+    if opts.handshake_only {
+        let subprotocol = response
+            .headers()
+            .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+            .map_or("none".into(), |v| String::from_utf8_lossy(v.as_bytes()));
+        println!(
+            "Handshake OK: {}, subprotocol: {subprotocol}",
+            response.status()
+        );
+    }
+    Ok(ws_stream)
+}
+
+// ("TLS1.3", "TLS13_AES_256_GCM_SHA384") for --print-cipher and --emit-connect-event
+fn negotiated_tls(conn: &rustls::ClientConnection) -> (String, String) {
+    let version = match conn.protocol_version() {
+        Some(rustls::ProtocolVersion::TLSv1_2) => "TLS1.2".to_string(),
+        Some(rustls::ProtocolVersion::TLSv1_3) => "TLS1.3".to_string(),
+        Some(other) => format!("{other:?}"),
+        None => "unknown version".to_string(),
+    };
+    let suite = conn
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .unwrap_or_else(|| "unknown cipher suite".to_string());
+    (version, suite)
+}
+
+// --print-response-header: bare values on stdout, one line each (a repeated header gives
+// several lines), for scripts to pick up
+fn print_response_headers(response: &Response, names: &[String]) {
+    for name in names {
+        let mut values = response.headers().get_all(name.as_str()).iter().peekable();
+        if values.peek().is_none() {
+            warn(&format!("response has no {name} header"));
+        }
+        for value in values {
+            println!("{}", String::from_utf8_lossy(value.as_bytes()));
+        }
+    }
+}
 
+// The server must select one of the offered subprotocols; selecting none only warns
+// unless --require-subprotocol is set
+fn check_subprotocol(
+    response: &Response,
+    offered: &[String],
+    required: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let selected = response
+        .headers()
+        .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .unwrap_or("");
+    if selected.is_empty() {
+        let message = format!(
+            "server did not select a subprotocol (offered: {})",
+            offered.join(", ")
+        );
+        if required {
+            return Err(ExitError::new(EXIT_HANDSHAKE, message).into());
+        }
+        warn(&message);
+    } else if !offered.iter().any(|p| p == selected) {
+        return Err(ExitError::new(
+            EXIT_HANDSHAKE,
+            format!("server selected subprotocol \"{selected}\", which was not offered"),
+        )
+        .into());
+    }
     Ok(())
 }
 
+// Errors where a TLS handshake met a server that doesn't speak TLS (it resets, hangs up,
+// or answers with plaintext that rustls can't parse). Every other rustls error comes as
+// InvalidData too, certificate verification failures included: those must never downgrade
+fn is_tls_refusal(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<tokio_tungstenite::tungstenite::Error>() {
+        Some(tokio_tungstenite::tungstenite::Error::Io(err)) => match err.kind() {
+            std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::UnexpectedEof => true,
+            std::io::ErrorKind::InvalidData => matches!(
+                err.get_ref()
+                    .and_then(|inner| inner.downcast_ref::<rustls::Error>()),
+                Some(rustls::Error::InvalidMessage(_))
+            ),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Upper bound for /repeat, so a typo can't flood the server
+const REPEAT_MAX: usize = 10_000;
+
 // Handle slash commands for control frames. Returns true if connection should close.
 async fn handle_slash_command(
     line: &str,
     write: &mut (impl SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin),
+    session: &mut Session,
 ) -> Result<bool, tokio_tungstenite::tungstenite::Error> {
     let tokens: Vec<&str> = line.split_whitespace().collect();
     let cmd = tokens
@@ -203,7 +3050,10 @@ async fn handle_slash_command(
                 .get(1)
                 .and_then(|v| v.parse::<u16>().ok())
                 .unwrap_or(1000);
-            let reason = tokens.get(2..).unwrap_or(&[]).join(" ");
+            let mut reason = tokens.get(2..).unwrap_or(&[]).join(" ");
+            if reason.is_empty() {
+                reason = session.close_reason.clone().unwrap_or_default();
+            }
             let frame = CloseFrame {
                 code: CloseCode::from(code),
                 reason: reason.into(),
@@ -211,47 +3061,675 @@ async fn handle_slash_command(
             write.send(Message::Close(Some(frame))).await?;
             return Ok(true);
         }
+        "connect" => {
+            let Some(url) = tokens.get(1) else {
+                diag("error: Usage: /connect <url>");
+                return Ok(false);
+            };
+            write.send(Message::Close(None)).await?;
+            session.switch_to = Some(url.to_string());
+            return Ok(true);
+        }
+        "repeat" => {
+            // The payload is sent literally, even if it starts with '/'
+            let count = tokens.get(1).and_then(|v| v.parse::<usize>().ok());
+            let text = tokens.get(2..).unwrap_or(&[]).join(" ");
+            match count {
+                Some(count @ 1..=REPEAT_MAX) if !text.is_empty() => {
+                    if !session.raw_output {
+                        session.echo(&format!("{text} (x{count})"));
+                    }
+                    for _ in 0..count {
+                        write.feed(Message::Text(text.clone())).await?;
+                    }
+                    write.flush().await?;
+                }
+                _ => diag(&format!("error: Usage: /repeat <1-{REPEAT_MAX}> <text>")),
+            }
+        }
+        "rawtext" => {
+            // Frame-level write: Message::Text would insist on valid UTF-8
+            match decode_hex(&tokens.get(1..).unwrap_or(&[]).concat()) {
+                Some(data) if !data.is_empty() => {
+                    if !session.raw_output {
+                        session.echo(&format!("(raw text, {} bytes)", data.len()));
+                    }
+                    let frame = Frame::message(data, OpCode::Data(Data::Text), true);
+                    write.send(Message::Frame(frame)).await?;
+                }
+                _ => diag("error: Usage: /rawtext <hex bytes>"),
+            }
+        }
+        "cbor" => {
+            // The rest of the line as typed: joining tokens would squeeze spaces inside strings
+            let json = line.trim_start().strip_prefix("/cbor").unwrap_or("").trim();
+            match serde_json::from_str::<serde_json::Value>(json) {
+                Ok(value) => {
+                    if !session.raw_output {
+                        session.echo(&value.to_string());
+                    }
+                    write.send(Message::Binary(cbor::encode(&value))).await?;
+                }
+                Err(err) => diag(&format!("error: Usage: /cbor <json> ({err})")),
+            }
+        }
+        "pause" => session.paused = true,
+        "resume" => session.resume(),
+        "flush" => session.flush(),
+        "macro" => return handle_macro(line, write, session).await,
         _ => {
-            eprintln!("error: Unrecognized slash command.");
+            diag("error: Unrecognized slash command.");
         }
     }
 
     Ok(false)
 }
 
+// /macro define|add <name> <line>, /macro run <name>, /macro list. A macro is a list of lines,
+// each sent as text or run as a slash command (but not /macro) like a typed line
+async fn handle_macro(
+    line: &str,
+    write: &mut (impl SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin),
+    session: &mut Session,
+) -> Result<bool, tokio_tungstenite::tungstenite::Error> {
+    // The recorded line keeps its own spacing, so only split off the first words
+    let args = line
+        .trim_start()
+        .strip_prefix("/macro")
+        .unwrap_or("")
+        .trim_start();
+    let (action, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let (name, body) = rest
+        .trim_start()
+        .split_once(' ')
+        .unwrap_or((rest.trim(), ""));
+
+    match action {
+        "define" | "add" if !name.is_empty() && !body.is_empty() => {
+            let lines = session.macros.entry(name.to_string()).or_default();
+            if action == "define" {
+                lines.clear();
+            }
+            lines.push(body.to_string());
+        }
+        "run" if !name.is_empty() => {
+            let Some(lines) = session.macros.get(name).cloned() else {
+                diag(&format!("error: No macro named {name}"));
+                return Ok(false);
+            };
+            for line in lines {
+                if line.split_whitespace().next() == Some("/macro") {
+                    diag("error: Macros can't run /macro");
+                } else if line.starts_with('/') {
+                    if Box::pin(handle_slash_command(&line, write, session)).await? {
+                        return Ok(true);
+                    }
+                } else {
+                    if !session.raw_output {
+                        session.echo(&line);
+                    }
+                    write.send(Message::Text(line)).await?;
+                }
+            }
+        }
+        "list" => {
+            for (name, lines) in &session.macros {
+                diag(&format!("{name}: {}", lines.join(" | ")));
+            }
+        }
+        _ => diag("error: Usage: /macro define|add <name> <line>, /macro run <name>, /macro list"),
+    }
+    Ok(false)
+}
+
+// "c328" or "c3 28" (already joined by the caller) to bytes; None on odd length or non-hex
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+// --json-pointer: the value at `pointer` in a JSON text message; strings without their quotes
+fn json_pointer_value(text: &str, pointer: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_str(text).ok()?;
+    match json.pointer(pointer)? {
+        serde_json::Value::String(s) => Some(s.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+// Print a binary payload the way --binary-display (or --hexdump / --raw-output) asks for
+fn show_binary(
+    data: &[u8],
+    mode: BinaryDisplay,
+    framing: OutputFraming,
+    dedup: bool,
+    session: &mut Session,
+) -> std::io::Result<()> {
+    let line = match mode {
+        BinaryDisplay::Text => String::from_utf8_lossy(data).into_owned(),
+        BinaryDisplay::Hex => data.iter().map(|b| format!("{b:02x}")).collect(),
+        BinaryDisplay::Base64 => BASE64_STANDARD.encode(data),
+        BinaryDisplay::Hexdump => {
+            session.show(hexdump("binary", data));
+            return Ok(());
+        }
+        BinaryDisplay::Cbor => match cbor::decode(data) {
+            Some(value) => serde_json::to_string_pretty(&value).unwrap_or_default(),
+            None => data.iter().map(|b| format!("{b:02x}")).collect(),
+        },
+        BinaryDisplay::Raw => return write_raw(data, framing),
+    };
+    session.show_incoming(&line, dedup);
+    Ok(())
+}
+
+// --output-format: the same rendering for text and binary payloads. A JSON envelope is
+// printed as is, one object per line for jq and friends; hex and base64 go through
+// --remote-echo-format like any other payload
+fn show_formatted(message: &Message, opts: &Opts, session: &mut Session) {
+    let (kind, data) = match message {
+        Message::Text(text) => ("text", text.as_bytes()),
+        Message::Binary(data) => ("binary", data.as_slice()),
+        _ => return,
+    };
+    match opts.output_format {
+        OutputFormat::Json => {
+            let payload = match message {
+                Message::Text(text) => text.clone(),
+                _ => BASE64_STANDARD.encode(data),
+            };
+            let envelope = serde_json::json!({
+                "type": kind,
+                "size": data.len(),
+                "data": payload,
+            });
+            session.received_count += 1;
+            session.show_payload(envelope.to_string(), opts.dedup);
+        }
+        OutputFormat::Hex => {
+            let hex: String = data.iter().map(|b| format!("{b:02x}")).collect();
+            session.show_incoming(&hex, opts.dedup);
+        }
+        OutputFormat::Base64 => session.show_incoming(&BASE64_STANDARD.encode(data), opts.dedup),
+        OutputFormat::Text => session.show_incoming(&String::from_utf8_lossy(data), opts.dedup),
+    }
+}
+
+// Fill in an echo template in one pass, so braces inside the message are left alone.
+// Unknown placeholders are kept as typed
+fn render_echo(template: &str, msg: &str, n: u64) -> String {
+    let mut out = String::with_capacity(template.len() + msg.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(tail) = rest.strip_prefix("{msg}") {
+            out.push_str(msg);
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("{n}") {
+            out.push_str(&n.to_string());
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("{ts}") {
+            let now = chrono::Local::now();
+            out.push_str(&now.to_rfc3339_opts(chrono::SecondsFormat::Millis, false));
+            rest = tail;
+        } else {
+            out.push('{');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Incoming text as printed: --strip-ansi applied
+fn display_text(line: String, opts: &Opts) -> String {
+    if opts.strip_ansi {
+        strip_ansi(&line)
+    } else {
+        line
+    }
+}
+
+// Drop ANSI escape sequences: CSI (ESC [ ... final byte), OSC (ESC ] ... BEL or ESC \) and
+// two-byte escapes. An unterminated sequence at the end is dropped as well
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                // Parameter and intermediate bytes, up to a final byte in @..~
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+// --output-split: the payload goes to the next numbered file in `dir`, and a line naming it
+// is printed in its place
+fn save_split(
+    dir: &std::path::Path,
+    ext: &str,
+    kind: &str,
+    data: &[u8],
+    session: &mut Session,
+) -> std::io::Result<()> {
+    let path = write_split(dir, ext, data, session)?;
+    session.show(format!(
+        "< ({kind}, {} bytes) -> {}",
+        data.len(),
+        path.display()
+    ));
+    Ok(())
+}
+
+// Write the payload to the next numbered --output-split file as received, and return its path
+fn write_split(
+    dir: &std::path::Path,
+    ext: &str,
+    data: &[u8],
+    session: &mut Session,
+) -> std::io::Result<PathBuf> {
+    session.split_count += 1;
+    let path = dir.join(format!("{:06}.{ext}", session.split_count));
+    fs::write(&path, data)
+        .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+    Ok(path)
+}
+
+// --on-message: pipe the payload through a shell command and show what it prints. A failing
+// command only warns, unless --on-message-fatal
+async fn on_message(
+    command: &str,
+    data: &[u8],
+    opts: &Opts,
+    session: &mut Session,
+) -> std::io::Result<()> {
+    match run_hook(command, &[], data).await {
+        Ok(output) => {
+            let output = String::from_utf8_lossy(&output);
+            let output = output.strip_suffix('\n').unwrap_or(&output);
+            if !output.is_empty() {
+                session.show(output.to_string());
+            }
+            Ok(())
+        }
+        Err(err) if opts.on_message_fatal => Err(std::io::Error::new(
+            err.kind(),
+            format!("--on-message: {err}"),
+        )),
+        Err(err) => {
+            warn(&format!("--on-message: {err}"));
+            Ok(())
+        }
+    }
+}
+
+// --on-reconnect: run the command with the reconnect number as $1, passing on what it prints
+async fn on_reconnect(
+    command: &str,
+    reconnects: u32,
+    fatal: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match run_hook(command, &[&reconnects.to_string()], b"").await {
+        Ok(output) => {
+            let output = String::from_utf8_lossy(&output);
+            let output = output.strip_suffix('\n').unwrap_or(&output);
+            if !output.is_empty() {
+                println!("{output}");
+            }
+            Ok(())
+        }
+        Err(err) if fatal => Err(format!("--on-reconnect: {err}").into()),
+        Err(err) => {
+            warn(&format!("--on-reconnect: {err}"));
+            Ok(())
+        }
+    }
+}
+
+// Spawn one process per call, with `args` after the command ($1, $2, ... in a shell);
+// its stderr passes straight through
+async fn run_hook(command: &str, args: &[&str], data: &[u8]) -> std::io::Result<Vec<u8>> {
+    use tokio::io::AsyncWriteExt;
+
+    #[cfg(unix)]
+    let mut cmd = tokio::process::Command::new("sh");
+    // The name after the script becomes $0
+    #[cfg(unix)]
+    cmd.arg("-c").arg(command).arg("wscrab");
+    #[cfg(not(unix))]
+    let mut cmd = tokio::process::Command::new("cmd");
+    #[cfg(not(unix))]
+    cmd.arg("/C").arg(command);
+    let mut child = cmd
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // A command that exits without reading its input is not an error
+    let mut stdin = child.stdin.take().unwrap();
+    if let Err(err) = stdin.write_all(data).await {
+        if err.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(err);
+        }
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "command failed ({})",
+            output.status
+        )));
+    }
+    Ok(output.stdout)
+}
+
+// --payload-size-limit: whether a payload is too big to print
+fn oversized(opts: &Opts, len: usize) -> bool {
+    opts.payload_size_limit.is_some_and(|limit| len > limit)
+}
+
+fn show_omitted(len: usize, opts: &Opts, session: &mut Session) {
+    let placeholder = format!("[message omitted: {}]", status::human_bytes(len as u64));
+    session.show_incoming(&placeholder, opts.dedup);
+}
+
+// --grep / --grep-invert: whether an incoming message is displayed
+fn grep_matches(opts: &Opts, text: &str) -> bool {
+    opts.grep
+        .as_ref()
+        .is_none_or(|re| re.is_match(text) != opts.grep_invert)
+}
+
 // Handle server messages; return true to exit the main loop
 async fn handle_message(
     message: Message,
     write: &mut (impl SinkExt<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin),
-    show_ping_pong: bool,
+    opts: &Opts,
+    session: &mut Session,
 ) -> Result<bool, tokio_tungstenite::tungstenite::Error> {
+    // --challenge-response: the first data message is the challenge, answered instead of shown
+    if let Some(secret) = opts.challenge_response.as_deref() {
+        let challenge = match &message {
+            Message::Text(text) => Some(text.as_bytes()),
+            Message::Binary(data) => Some(data.as_slice()),
+            _ => None,
+        };
+        if let Some(challenge) = challenge.filter(|_| session.challenge_pending) {
+            session.challenge_pending = false;
+            let digest: String = hmac_sha256(secret.as_bytes(), challenge)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            write.send(Message::Text(digest)).await?;
+            diag("Challenge received, response sent");
+            return Ok(false);
+        }
+    }
+
+    // --drop-incoming: gone before anything sees it, --until and --assert-receive included.
+    // A dropped ping still gets its pong, just no notification
+    // --conversation: any data message answers the line sent last, a dropped one included
+    if message.is_text() || message.is_binary() {
+        session.awaiting_reply = false;
+    }
+    let dropped = opts.drop_incoming.iter().any(|kind| kind.matches(&message));
+    if dropped && (message.is_text() || message.is_binary()) {
+        return Ok(false);
+    }
+
+    // --output-raw: saved before any display option (--grep and --trim-incoming included)
+    // gets to it; the display chain below then prints it instead of saving it
+    if let Some(dir) = opts.output_split.as_ref().filter(|_| opts.output_raw) {
+        match &message {
+            Message::Text(text) => {
+                write_split(dir, &opts.output_split_ext, text.as_bytes(), session)?;
+            }
+            Message::Binary(data) => {
+                write_split(dir, &opts.output_split_ext, data, session)?;
+            }
+            _ => {}
+        }
+    }
+    let output_split = opts.output_split.as_ref().filter(|_| !opts.output_raw);
+
     match message {
         Message::Text(text) => {
-            println!("< {text}");
+            let text = if opts.trim_incoming {
+                text.trim().to_string()
+            } else {
+                text
+            };
+            if !grep_matches(opts, &text) {
+                // Filtered out of the display only; --assert-receive and --until still see it
+            } else if let Some(command) = &opts.on_message {
+                on_message(command, text.as_bytes(), opts, session).await?;
+            } else if let Some(dir) = output_split {
+                save_split(
+                    dir,
+                    &opts.output_split_ext,
+                    "text",
+                    text.as_bytes(),
+                    session,
+                )?;
+            } else if opts.raw_output {
+                write_raw(text.as_bytes(), opts.output_framing)?;
+            } else if opts.json_pointer.is_none() && oversized(opts, text.len()) {
+                show_omitted(text.len(), opts, session);
+            } else if opts.output_format != OutputFormat::Text {
+                let text = display_text(text.clone(), opts);
+                show_formatted(&Message::Text(text), opts, session);
+            } else if opts.hexdump_all {
+                session.show(hexdump("text", text.as_bytes()));
+            } else if let Some(pointer) = &opts.json_pointer {
+                if let Some(value) = json_pointer_value(&text, pointer) {
+                    session.show_incoming(&display_text(value, opts), opts.dedup);
+                }
+            } else {
+                session.show_incoming(&display_text(text.clone(), opts), opts.dedup);
+            }
+            if opts
+                .assert_receive
+                .as_ref()
+                .is_some_and(|s| text.contains(s.as_str()))
+            {
+                session.assert_received = true;
+            }
+            if opts
+                .error_on_match
+                .as_ref()
+                .is_some_and(|re| re.is_match(&text))
+            {
+                session.error_match = Some(text);
+                write.send(Message::Close(None)).await?;
+                return Ok(true);
+            }
+            if opts.until.as_ref().is_some_and(|re| re.is_match(&text)) {
+                write.send(Message::Close(None)).await?;
+                return Ok(true);
+            }
         }
         Message::Binary(data) => {
-            let text = String::from_utf8_lossy(&data);
-            println!("< {text}");
+            if !grep_matches(opts, &String::from_utf8_lossy(&data)) {
+                // Filtered out of the display
+            } else if let Some(command) = &opts.on_message {
+                on_message(command, &data, opts, session).await?;
+            } else if let Some(dir) = output_split {
+                save_split(dir, &opts.output_split_ext, "binary", &data, session)?;
+            } else if opts.binary_display(&data) != BinaryDisplay::Raw
+                && oversized(opts, data.len())
+            {
+                show_omitted(data.len(), opts, session);
+            } else if opts.output_format != OutputFormat::Text {
+                show_formatted(&Message::Binary(data), opts, session);
+            } else {
+                show_binary(
+                    &data,
+                    opts.binary_display(&data),
+                    opts.output_framing,
+                    opts.dedup,
+                    session,
+                )?;
+            }
         }
         Message::Ping(data) => {
-            if show_ping_pong {
+            if opts.show_ping_pong && !dropped {
                 let text = String::from_utf8_lossy(&data);
-                println!("< Received ping (data: \"{text}\")");
+                session.show(format!("< Received ping (data: \"{text}\")"));
             }
+            // Replaces the pong tungstenite queued when it read the ping
+            let data = match &opts.auto_pong_data {
+                PongData::Echo => data,
+                PongData::Empty => Vec::new(),
+                PongData::Custom(custom) => custom.clone(),
+            };
             write.send(Message::Pong(data)).await?;
         }
         Message::Pong(data) => {
-            if show_ping_pong {
+            if opts.show_ping_pong && !dropped {
                 let text = String::from_utf8_lossy(&data);
-                println!("< Received pong (data: \"{text}\")");
+                session.show(format!("< Received pong (data: \"{text}\")"));
             }
         }
-        Message::Close(_) => return Ok(true),
+        Message::Close(frame) => {
+            // A close frame without a payload stands for 1005 (no status received)
+            session.close_code = Some(frame.as_ref().map_or(1005, |frame| frame.code.into()));
+            if let Some(frame) = frame {
+                let code = u16::from(frame.code);
+                diag(&format!(
+                    "Disconnected (code: {code} {}, reason: \"{}\")",
+                    close_code_description(code),
+                    frame.reason
+                ));
+            }
+            return Ok(true);
+        }
         Message::Frame(_) => {}
     }
     Ok(false)
 }
 
+// --hexdump: a header line, then `hexdump -C` layout (offset, 16 hex bytes, ASCII gutter)
+// ending with the total length
+fn hexdump(kind: &str, data: &[u8]) -> String {
+    let mut dump = format!("< ({kind}, {} bytes)\n", data.len());
+    for (row, chunk) in data.chunks(16).enumerate() {
+        let mut hex = String::new();
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(byte) => hex.push_str(&format!("{byte:02x} ")),
+                None => hex.push_str("   "),
+            }
+            if i == 7 {
+                hex.push(' ');
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b == b' ' || b.is_ascii_graphic() {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!("{:08x}  {hex} |{ascii}|\n", row * 16));
+    }
+    dump.push_str(&format!("{:08x}", data.len()));
+    dump
+}
+
+// Human-readable meaning of a close code (RFC 6455 section 7.4 and the IANA registry)
+fn close_code_description(code: u16) -> &'static str {
+    match code {
+        1000 => "normal closure",
+        1001 => "going away",
+        1002 => "protocol error",
+        1003 => "unsupported data",
+        1005 => "no status received",
+        1006 => "abnormal closure",
+        1007 => "invalid payload data",
+        1008 => "policy violation",
+        1009 => "message too big",
+        1010 => "mandatory extension missing",
+        1011 => "internal server error",
+        1012 => "service restart",
+        1013 => "try again later",
+        1014 => "bad gateway",
+        1015 => "TLS handshake failure",
+        3000..=3999 => "registered by a library or framework",
+        4000..=4999 => "application-defined",
+        _ => "unknown",
+    }
+}
+
+// --pretty-close: the IANA registry name of a close code and what it means
+fn close_code_explanation(code: u16) -> &'static str {
+    match code {
+        1000 => "Normal Closure: the connection completed its purpose",
+        1001 => "Going Away: the server is shutting down or the client navigated away",
+        1002 => "Protocol Error: the peer received a frame that violates the protocol",
+        1003 => "Unsupported Data: the peer cannot accept this type of data",
+        1005 => "No Status Rcvd: the close frame carried no status code",
+        1006 => "Abnormal Closure: the connection dropped without a close frame",
+        1007 => "Invalid Frame Payload Data: a message was not consistent with its type (e.g. bad UTF-8)",
+        1008 => "Policy Violation: a message violated the server's policy",
+        1009 => "Message Too Big: a message was too big to process",
+        1010 => "Mandatory Extension: the server did not negotiate a required extension",
+        1011 => "Internal Error: server encountered an unexpected condition",
+        1012 => "Service Restart: the server is restarting",
+        1013 => "Try Again Later: the server is overloaded, reconnect later",
+        1014 => "Bad Gateway: a gateway or proxy got an invalid response upstream",
+        1015 => "TLS Handshake: the TLS handshake failed",
+        3000..=4999 => "application-defined",
+        _ => "unknown: not a registered close code",
+    }
+}
+
+// --raw-output: payload bytes go to stdout as-is, flushed so pipes see them right away.
+// With --output-framing length-prefixed a reader can split them back into messages
+fn write_raw(data: &[u8], framing: OutputFraming) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    if framing == OutputFraming::LengthPrefixed {
+        // Messages are capped well below 4 GiB by tungstenite's max_message_size
+        stdout.write_all(&(data.len() as u32).to_be_bytes())?;
+    }
+    stdout.write_all(data)?;
+    stdout.flush()
+}
+
 // Parse "Header:Value" (split on the first colon only)
 fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), Box<dyn std::error::Error>> {
     let pos = header.find(':').ok_or("header must contain ':'")?;
@@ -262,22 +3740,96 @@ fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), Box<dyn std::
     Ok((name, value))
 }
 
+// --expand-placeholders: fill {host}, {port} (explicit or the scheme default) and {path}
+// from the resolved connect URL
+fn expand_placeholders(header: &str, uri: &http::Uri) -> String {
+    let default_port = if uri.scheme_str() == Some("wss") {
+        443
+    } else {
+        80
+    };
+    let port = uri.port_u16().unwrap_or(default_port);
+    header
+        .replace("{host}", uri.host().unwrap_or(""))
+        .replace("{port}", &port.to_string())
+        .replace("{path}", uri.path())
+}
+
+// Origin for --auto-origin: wss -> https, ws -> http, keeping host and explicit port
+fn origin_from_url(uri: &http::Uri) -> Result<String, Box<dyn std::error::Error>> {
+    let scheme = if uri.scheme_str() == Some("wss") {
+        "https"
+    } else {
+        "http"
+    };
+    let host = uri.host().ok_or("connect URL has no host")?;
+    Ok(match uri.port_u16() {
+        Some(port) => format!("{scheme}://{host}:{port}"),
+        None => format!("{scheme}://{host}"),
+    })
+}
+
+// Header name exactly as the user typed it (only call after parse_header succeeded)
+fn header_name_as_typed(header: &str) -> &str {
+    header.split(':').next().unwrap_or("").trim()
+}
+
+// --listen --cert: serve wss:// with the certificate chain and key from one PEM file
+fn server_tls(path: &std::path::Path) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+    let (certs, key) = load_certs_and_key(&fs::read(path)?)?;
+    let Some(key) = key else {
+        return Err(format!(
+            "{} has no private key; --listen needs the certificate chain and key in one PEM file",
+            path.display()
+        )
+        .into());
+    };
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
 // Build TLS config: support self-signed via --no-check and custom cert via --cert
 fn build_tls_config(
     cert_path: Option<&std::path::Path>,
     no_check: bool,
+    pin: Option<&[u8; 32]>,
+    verify_depth: Option<usize>,
+    trust_system_store: bool,
+    insecure_hosts: &[String],
+    host: &str,
 ) -> Result<ClientConfig, Box<dyn std::error::Error>> {
     let mut root_store = RootCertStore::empty();
     if !no_check {
         root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
     }
 
+    // OS trust store on top of the bundled roots (e.g. internal CAs installed by IT)
+    if trust_system_store {
+        let native = rustls_native_certs::load_native_certs();
+        for err in &native.errors {
+            warn(&format!("system certificate store: {err}"));
+        }
+        let (added, _) = root_store.add_parsable_certificates(native.certs);
+        if added == 0 {
+            return Err("no usable certificates found in the system store".into());
+        }
+    }
+
+    // A directory or mapping file holds one client identity per host instead
+    let resolver = match cert_path {
+        Some(path) => HostCertResolver::load(path, host)?,
+        None => None,
+    };
+
     // Allow cert chain + private key in one PEM file; DER is treated as cert only
-    let (certs, key) = if let Some(path) = cert_path {
-        let bytes = fs::read(path)?;
-        load_certs_and_key(&bytes)?
-    } else {
-        (Vec::new(), None)
+    let (certs, key) = match cert_path {
+        Some(path) if resolver.is_none() => {
+            let bytes = fs::read(path)?;
+            load_certs_and_key(&bytes)?
+        }
+        _ => (Vec::new(), None),
     };
 
     if !certs.is_empty() && !no_check {
@@ -286,15 +3838,39 @@ fn build_tls_config(
         }
     }
 
-    let builder = if no_check {
+    let builder = if let Some(pin) = pin {
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinVerifier::new(pin)))
+    } else if no_check {
         ClientConfig::builder()
             .dangerous()
             .with_custom_certificate_verifier(Arc::new(NoVerifier))
+    } else if verify_depth.is_some() || !insecure_hosts.is_empty() {
+        let mut verifier: Arc<dyn ServerCertVerifier> =
+            WebPkiServerVerifier::builder(Arc::new(root_store)).build()?;
+        if let Some(max_intermediates) = verify_depth {
+            verifier = Arc::new(DepthLimitVerifier {
+                inner: verifier,
+                max_intermediates,
+            });
+        }
+        if !insecure_hosts.is_empty() {
+            verifier = Arc::new(InsecureHostsVerifier {
+                inner: verifier,
+                hosts: insecure_hosts.to_vec(),
+            });
+        }
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
     } else {
         ClientConfig::builder().with_root_certificates(root_store)
     };
 
-    let config = if let Some(key) = key {
+    let config = if let Some(resolver) = resolver {
+        builder.with_client_cert_resolver(Arc::new(resolver))
+    } else if let Some(key) = key {
         builder.with_client_auth_cert(certs, key)?
     } else {
         builder.with_no_client_auth()
@@ -303,6 +3879,96 @@ fn build_tls_config(
     Ok(config)
 }
 
+// Client identities for --cert <directory|mapping file>, answering with the one for `host`
+// (or the "*" default). A directory holds <host>.pem files plus an optional default.pem; a
+// mapping file has "host = path" lines. Each identity is a PEM with cert chain and key
+#[derive(Debug)]
+struct HostCertResolver {
+    host: String,
+    identities: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl HostCertResolver {
+    // None when `path` is a plain certificate file rather than a directory or mapping file
+    fn load(
+        path: &std::path::Path,
+        host: &str,
+    ) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let mut files = Vec::new();
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let file = entry?.path();
+                if file.extension().is_some_and(|ext| ext == "pem") {
+                    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+                    let name = if stem == "default" {
+                        "*".to_string()
+                    } else {
+                        stem.to_string()
+                    };
+                    files.push((name, file));
+                }
+            }
+        } else {
+            let bytes = fs::read(path)?;
+            // PEM and DER certificates never parse as a text mapping file
+            let Ok(text) = std::str::from_utf8(&bytes) else {
+                return Ok(None);
+            };
+            if text.contains("-----BEGIN") {
+                return Ok(None);
+            }
+            let base = path.parent().unwrap_or(std::path::Path::new("."));
+            for line in text.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (name, file) = line.split_once('=').ok_or_else(|| {
+                    format!(
+                        "{}: expected \"host = path\", got \"{line}\"",
+                        path.display()
+                    )
+                })?;
+                files.push((name.trim().to_string(), base.join(file.trim())));
+            }
+        }
+
+        let mut identities = HashMap::new();
+        for (name, file) in files {
+            let (certs, key) = load_certs_and_key(&fs::read(&file)?)?;
+            let key = key.ok_or_else(|| format!("{}: no private key", file.display()))?;
+            let key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)?;
+            identities.insert(
+                name.to_ascii_lowercase(),
+                Arc::new(CertifiedKey::new(certs, key)),
+            );
+        }
+        Ok(Some(Self {
+            host: host.to_ascii_lowercase(),
+            identities,
+        }))
+    }
+
+    fn identity(&self) -> Option<&Arc<CertifiedKey>> {
+        self.identities
+            .get(&self.host)
+            .or_else(|| self.identities.get("*"))
+    }
+}
+
+impl ResolvesClientCert for HostCertResolver {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        self.identity().cloned()
+    }
+
+    fn has_certs(&self) -> bool {
+        self.identity().is_some()
+    }
+}
+
 // Load PEM/DER certs and keys (PEM supports PKCS#8/RSA/EC)
 fn load_certs_and_key(
     bytes: &[u8],
@@ -333,3 +3999,62 @@ fn load_certs_and_key(
         Ok((vec![CertificateDer::from(bytes.to_vec())], None))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_paste() -> Paste {
+        Paste {
+            enabled: true,
+            ..Paste::default()
+        }
+    }
+
+    #[test]
+    fn paste_off_passes_lines_through() {
+        let mut paste = Paste::default();
+        assert_eq!(
+            paste.feed(format!("{PASTE_START}a{PASTE_END}")),
+            Some(format!("{PASTE_START}a{PASTE_END}"))
+        );
+    }
+
+    #[test]
+    fn paste_on_one_line_is_one_message() {
+        let mut paste = enabled_paste();
+        assert_eq!(
+            paste.feed(format!("{PASTE_START}a b{PASTE_END}")),
+            Some("a b".into())
+        );
+        assert_eq!(paste.feed("typed".into()), Some("typed".into()));
+    }
+
+    #[test]
+    fn paste_over_several_reads_is_sent_as_one_message() {
+        let mut paste = enabled_paste();
+        assert_eq!(paste.feed(format!("{PASTE_START}first")), None);
+        assert_eq!(paste.feed("second".into()), None);
+        assert_eq!(
+            paste.feed(format!("third{PASTE_END}")),
+            Some("first\nsecond\nthird".into())
+        );
+    }
+
+    #[test]
+    fn paste_end_marker_alone_adds_no_empty_line() {
+        let mut paste = enabled_paste();
+        assert_eq!(paste.feed(format!("{PASTE_START}first")), None);
+        assert_eq!(paste.feed("second".into()), None);
+        assert_eq!(paste.feed(PASTE_END.into()), Some("first\nsecond".into()));
+    }
+
+    #[test]
+    fn paste_unterminated_at_eof_sends_nothing() {
+        let mut paste = enabled_paste();
+        assert_eq!(paste.feed(format!("{PASTE_START}first")), None);
+        assert_eq!(paste.feed("second".into()), None);
+        // Input ends here: the partial block is still held, not sent line by line
+        assert_eq!(paste.block, Some(vec!["first".into(), "second".into()]));
+    }
+}
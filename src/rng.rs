@@ -0,0 +1,28 @@
+// xorshift64*, for --every-jitter and the UUIDs of --template / --trace-header. Test traffic
+// only: nothing secret depends on it
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Rng(u64);
+
+impl Rng {
+    // Seeded from the clock and pid, so clients started together still differ
+    pub fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self((nanos ^ (u64::from(std::process::id()) << 32)) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
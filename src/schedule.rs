@@ -0,0 +1,58 @@
+// --every / --send-rate-ramp / --every-jitter: when the next scheduled message is due
+
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+
+// --send-rate-ramp: messages per second, from `start` to `end` over `duration`
+#[derive(Clone, Copy, Debug)]
+pub struct Ramp {
+    pub start: f64,
+    pub end: f64,
+    pub duration: Duration,
+}
+
+impl Ramp {
+    fn rate(&self, elapsed: Duration) -> f64 {
+        if elapsed >= self.duration {
+            return self.end;
+        }
+        let progress = elapsed.as_secs_f64() / self.duration.as_secs_f64();
+        self.start + (self.end - self.start) * progress
+    }
+}
+
+pub struct Schedule {
+    every: Duration,
+    ramp: Option<Ramp>,
+    started: Instant,
+    // Fraction of the interval each one may move either way
+    jitter: f64,
+    rng: Rng,
+}
+
+impl Schedule {
+    pub fn new(every: Duration, ramp: Option<Ramp>, jitter: f64) -> Self {
+        Self {
+            every,
+            ramp,
+            started: Instant::now(),
+            jitter,
+            rng: Rng::new(),
+        }
+    }
+
+    // The interval until the next send: `every`, or one over the ramp's current rate, scaled
+    // by a random factor in [1 - jitter, 1 + jitter]
+    pub fn next_interval(&mut self) -> Duration {
+        let interval = match &self.ramp {
+            Some(ramp) => Duration::from_secs_f64(1.0 / ramp.rate(self.started.elapsed())),
+            None => self.every,
+        };
+        if self.jitter == 0.0 {
+            return interval;
+        }
+        let offset = self.jitter * (2.0 * self.rng.next_unit() - 1.0);
+        interval.mul_f64(1.0 + offset)
+    }
+}
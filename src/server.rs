@@ -0,0 +1,192 @@
+// Server-side modes: accept WebSocket connections instead of connecting out
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async, WebSocketStream};
+
+// --echo-server: serve clients one after another (just one with --once)
+pub async fn echo_server(port: u16, once: bool) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    println!("Listening on port {port} (press CTRL+C to quit)");
+
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        println!("Client connected: {peer}");
+        // A misbehaving client only ends its own connection, not the server
+        match echo_connection(tcp).await {
+            Ok(()) => println!("Client disconnected: {peer}"),
+            Err(err) => crate::diag(&format!("Client {peer} disconnected: {err}")),
+        }
+        if once {
+            return Ok(());
+        }
+    }
+}
+
+// Send every text/binary message back. Pings are answered by tungstenite itself, and reading
+// on after a Close lets it finish the closing handshake
+async fn echo_connection(tcp: TcpStream) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+    let mut ws_stream = accept_async(tcp).await?;
+    while let Some(message) = ws_stream.next().await {
+        let message = message?;
+        if message.is_text() || message.is_binary() {
+            ws_stream.send(message).await?;
+        }
+    }
+    Ok(())
+}
+
+// Messages queued per client before a slow one starts skipping
+const BROADCAST_CAPACITY: usize = 1024;
+
+// --listen: exchange messages with connecting clients; stdin lines go to every client.
+// Without --broadcast only one client is served at a time, further ones are turned away.
+// With `tls` (--cert) the endpoint is wss://. With `wait_for_open` (--wait-for-open), lines
+// read while no client is connected are kept for the next one instead of being dropped
+pub async fn listen(
+    port: u16,
+    relay: bool,
+    tls: Option<TlsAcceptor>,
+    wait_for_open: bool,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    println!("Listening on port {port} ({scheme}, press CTRL+C to quit)");
+
+    let (tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+    // Counted from accept (not from the finished handshake) so the single-client check can't race
+    let clients = Arc::new(AtomicUsize::new(0));
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdin_open = true;
+    let mut pending = VecDeque::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (tcp, peer) = accepted?;
+                if !relay && clients.load(Ordering::SeqCst) > 0 {
+                    crate::warn(&format!(
+                        "rejected {peer}: already serving a client (use --broadcast for several)"
+                    ));
+                    continue;
+                }
+                clients.fetch_add(1, Ordering::SeqCst);
+                let client = Client {
+                    peer,
+                    tx: tx.clone(),
+                    clients: clients.clone(),
+                    relay,
+                    tls: tls.clone(),
+                };
+                let rx = tx.subscribe();
+                // Queued in the new client's receiver, so they go out right after its handshake
+                for message in pending.drain(..) {
+                    let _ = tx.send(message);
+                }
+                tokio::spawn(client.serve(tcp, rx));
+            }
+            // Keep serving after stdin ends (e.g. when run in the background)
+            line = lines.next_line(), if stdin_open => {
+                match line? {
+                    Some(line) if wait_for_open && tx.receiver_count() == 0 => {
+                        if pending.len() == BROADCAST_CAPACITY {
+                            pending.pop_front();
+                            crate::warn("no client yet, dropped the oldest waiting line");
+                        }
+                        pending.push_back(Message::Text(line));
+                    }
+                    Some(line) => {
+                        // No receivers just means nobody is connected yet
+                        let _ = tx.send(Message::Text(line));
+                    }
+                    None => stdin_open = false,
+                }
+            }
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+// One connected client in listen mode
+struct Client {
+    peer: SocketAddr,
+    tx: broadcast::Sender<Message>,
+    clients: Arc<AtomicUsize>,
+    relay: bool,
+    tls: Option<TlsAcceptor>,
+}
+
+impl Client {
+    // Print what the client sends (relaying it with --broadcast) and forward channel messages to it
+    async fn serve(self, tcp: TcpStream, mut rx: broadcast::Receiver<Message>) {
+        let peer = self.peer;
+        match &self.tls {
+            Some(acceptor) => match acceptor.accept(tcp).await {
+                Ok(tls_stream) => self.handshake(tls_stream, &mut rx).await,
+                Err(err) => crate::diag(&format!("Client {peer}: TLS handshake failed: {err}")),
+            },
+            None => self.handshake(tcp, &mut rx).await,
+        }
+        let count = self.clients.fetch_sub(1, Ordering::SeqCst) - 1;
+        println!("Client disconnected: {peer} ({count} connected)");
+    }
+
+    async fn handshake<S>(&self, stream: S, rx: &mut broadcast::Receiver<Message>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let peer = self.peer;
+        match accept_async(stream).await {
+            Ok(ws_stream) => {
+                let count = self.clients.load(Ordering::SeqCst);
+                println!("Client connected: {peer} ({count} connected)");
+                if let Err(err) = self.exchange(ws_stream, rx).await {
+                    crate::diag(&format!("Client {peer}: {err}"));
+                }
+            }
+            Err(err) => crate::diag(&format!("Client {peer}: handshake failed: {err}")),
+        }
+    }
+
+    async fn exchange<S>(
+        &self,
+        ws_stream: WebSocketStream<S>,
+        rx: &mut broadcast::Receiver<Message>,
+    ) -> Result<(), tokio_tungstenite::tungstenite::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut write, mut read) = ws_stream.split();
+        loop {
+            tokio::select! {
+                message = read.next() => {
+                    let Some(message) = message else { return Ok(()) };
+                    let message = message?;
+                    if message.is_text() || message.is_binary() {
+                        println!("< {}", String::from_utf8_lossy(&message.clone().into_data()));
+                        if self.relay {
+                            let _ = self.tx.send(message);
+                        }
+                    }
+                }
+                outgoing = rx.recv() => match outgoing {
+                    Ok(message) => write.send(message).await?,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        crate::warn(&format!("client {}: too slow, skipped {n} messages", self.peer));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+// --status-line / --stats / --compression-stats: traffic counters, and the line that shows them.
+// --summary-on-signal prints the --stats line on demand
+
+use std::io::IsTerminal;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::Sink;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+// Written by Wire (socket side) and Metered (message side), read by the renderer
+#[derive(Default)]
+pub struct Traffic {
+    // Bytes written to / read from the socket
+    sent: AtomicU64,
+    received: AtomicU64,
+    // Bytes that will have been sent once every frame handed to tungstenite is out
+    queued: AtomicU64,
+    // Text/binary messages, and frames of any kind (fragments and control frames included)
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    // Text/binary payload bytes, before any framing
+    payload_sent: AtomicU64,
+    payload_received: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add_sent(&self, n: usize) {
+        self.sent.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, n: usize) {
+        self.received.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_message_received(&self, payload_len: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.payload_received
+            .fetch_add(payload_len as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_frames_sent(&self, n: u64) {
+        self.frames_sent.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_frames_received(&self, n: u64) {
+        self.frames_received.fetch_add(n, Ordering::Relaxed);
+    }
+
+    // A frame of `payload_len` bytes is on its way out
+    pub fn queue(&self, payload_len: usize, masked: bool) {
+        self.queued
+            .fetch_add(frame_len(payload_len, masked), Ordering::Relaxed);
+    }
+
+    // New connection: nothing of it is in flight yet
+    pub fn reset(&self) {
+        self.queued
+            .store(self.sent.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    // Queued in tungstenite or the socket layer but not written yet
+    fn in_flight(&self) -> u64 {
+        let queued = self.queued.load(Ordering::Relaxed);
+        queued.saturating_sub(self.sent.load(Ordering::Relaxed))
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "sent {}, received {}",
+            human_bytes(self.sent.load(Ordering::Relaxed)),
+            human_bytes(self.received.load(Ordering::Relaxed))
+        )
+    }
+
+    // --stats: "sent: 3 messages, recv: 10 messages", with "/ 34 frames" for --count-frames
+    pub fn stats(&self, frames: bool) -> String {
+        let side = |messages: &AtomicU64, frame_count: &AtomicU64| {
+            let messages = messages.load(Ordering::Relaxed);
+            if frames {
+                let frame_count = frame_count.load(Ordering::Relaxed);
+                format!("{messages} messages / {frame_count} frames")
+            } else {
+                format!("{messages} messages")
+            }
+        };
+        format!(
+            "sent: {}, recv: {}",
+            side(&self.messages_sent, &self.frames_sent),
+            side(&self.messages_received, &self.frames_received)
+        )
+    }
+
+    // --compression-stats: payload bytes against socket bytes, "ratio" being payload / wire
+    pub fn compression(&self) -> String {
+        let side = |payload: &AtomicU64, wire: &AtomicU64| {
+            let payload = payload.load(Ordering::Relaxed);
+            let wire = wire.load(Ordering::Relaxed);
+            let ratio = if wire == 0 {
+                0.0
+            } else {
+                payload as f64 / wire as f64
+            };
+            format!(
+                "{} payload, {} on wire (ratio {ratio:.2})",
+                human_bytes(payload),
+                human_bytes(wire)
+            )
+        };
+        format!(
+            "sent {}; recv {}",
+            side(&self.payload_sent, &self.sent),
+            side(&self.payload_received, &self.received)
+        )
+    }
+}
+
+// Size on the wire of a frame with this payload (RFC 6455 section 5.2)
+fn frame_len(payload_len: usize, masked: bool) -> u64 {
+    let extended = match payload_len {
+        0..=125 => 0,
+        126..=65535 => 2,
+        _ => 8,
+    };
+    let mask = if masked { 4 } else { 0 };
+    (2 + extended + mask + payload_len) as u64
+}
+
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{n} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+// Redraw the status line on stderr once a second; None when stderr isn't a terminal
+// (the caller prints the summary at the end instead)
+pub fn spawn_renderer(traffic: &'static Traffic) -> Option<JoinHandle<()>> {
+    if !std::io::stderr().is_terminal() {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        let period = Duration::from_secs(1);
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        let mut last = (0, 0);
+        loop {
+            ticker.tick().await;
+            let sent = traffic.sent.load(Ordering::Relaxed);
+            let received = traffic.received.load(Ordering::Relaxed);
+            crate::redraw(&format!(
+                "send {}/s, recv {}/s, in flight {}",
+                human_bytes(sent - last.0),
+                human_bytes(received - last.1),
+                human_bytes(traffic.in_flight())
+            ));
+            last = (sent, received);
+        }
+    }))
+}
+
+// --summary-on-signal: each SIGUSR1 is a request for the stats so far. Without the flag (and
+// off Unix) recv() never resolves, and SIGUSR1 keeps its default action
+pub struct SummarySignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl SummarySignal {
+    pub fn new(enabled: bool) -> std::io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let signal = enabled
+                .then(|| signal(SignalKind::user_defined1()))
+                .transpose()?;
+            Ok(Self { signal })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = enabled;
+            Ok(Self {})
+        }
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+// Message sink that counts every frame handed to it as queued
+pub struct Metered<S> {
+    inner: S,
+    traffic: Option<&'static Traffic>,
+    masked: bool,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, traffic: Option<&'static Traffic>, masked: bool) -> Self {
+        Self {
+            inner,
+            traffic,
+            masked,
+        }
+    }
+}
+
+impl<S: Sink<Message> + Unpin> Sink<Message> for Metered<S> {
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<(), S::Error> {
+        if let Some(traffic) = self.traffic {
+            let payload_len = match &message {
+                Message::Frame(frame) => frame.payload().len(),
+                Message::Close(Some(frame)) => 2 + frame.reason.len(),
+                message => message.len(),
+            };
+            traffic.queue(payload_len, self.masked);
+            if matches!(
+                message,
+                Message::Text(_) | Message::Binary(_) | Message::Frame(_)
+            ) {
+                traffic.messages_sent.fetch_add(1, Ordering::Relaxed);
+                traffic
+                    .payload_sent
+                    .fetch_add(payload_len as u64, Ordering::Relaxed);
+            }
+        }
+        Pin::new(&mut self.inner).start_send(message)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,73 @@
+// --template / --repeat: a message rendered afresh for every send. Also the UUIDs of
+// --trace-header
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::rng::Rng;
+
+pub struct Template {
+    text: String,
+    rng: Rng,
+}
+
+impl Template {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            rng: Rng::new(),
+        }
+    }
+
+    // Fill in {n} (the send index, from 1), {ts} (Unix time in milliseconds) and {uuid} (a
+    // random version 4 UUID) in one pass. Unknown placeholders are kept as typed
+    pub fn render(&mut self, n: u64) -> String {
+        let mut out = String::with_capacity(self.text.len() + 32);
+        let mut rest = self.text.as_str();
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if let Some(tail) = rest.strip_prefix("{n}") {
+                out.push_str(&n.to_string());
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("{ts}") {
+                out.push_str(&unix_time().as_millis().to_string());
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("{uuid}") {
+                let uuid = uuid_v4(self.rng.next_u64(), self.rng.next_u64());
+                out.push_str(&uuid);
+                rest = tail;
+            } else {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+// A one-off random UUID (--trace-header)
+pub fn random_uuid() -> String {
+    let mut rng = Rng::new();
+    uuid_v4(rng.next_u64(), rng.next_u64())
+}
+
+fn unix_time() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// RFC 9562 layout: version 4 in the high nibble of byte 6, variant 10 in byte 8
+fn uuid_v4(high: u64, low: u64) -> String {
+    let high = (high & !0xf000) | 0x4000;
+    let low = (low & !(0b11 << 62)) | (0b10 << 62);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
@@ -0,0 +1,295 @@
+// Byte-level view of the connection underneath the WebSocket layer, for options that need
+// to see or rewrite the raw bytes tungstenite reads and writes
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::status::Traffic;
+
+// Stream wrapper; passes everything through unchanged unless an option is turned on
+pub struct Wire<S> {
+    inner: S,
+    // --no-mask: strip the masking from outgoing frames once the HTTP upgrade request is out
+    unmask: bool,
+    handshake_sent: bool,
+    // Written by tungstenite but not yet rewritten (incomplete frame) / not yet sent
+    pending: Vec<u8>,
+    out: Vec<u8>,
+    // --show-raw-handshake: copies of the upgrade request/response, printed once complete
+    request_log: Option<HandshakeLog>,
+    response_log: Option<HandshakeLog>,
+    // --status-line / --stats: bytes actually written/read, and the frames in them
+    traffic: Option<&'static Traffic>,
+    frames_out: FrameCounter,
+    frames_in: FrameCounter,
+}
+
+impl<S> Wire<S> {
+    pub fn new(
+        inner: S,
+        unmask: bool,
+        show_handshake: bool,
+        traffic: Option<&'static Traffic>,
+    ) -> Self {
+        Self {
+            inner,
+            unmask,
+            handshake_sent: false,
+            pending: Vec::new(),
+            out: Vec::new(),
+            request_log: show_handshake.then(|| HandshakeLog::new("request")),
+            response_log: show_handshake.then(|| HandshakeLog::new("response")),
+            traffic,
+            frames_out: FrameCounter::default(),
+            frames_in: FrameCounter::default(),
+        }
+    }
+
+    // Bytes accepted for writing: logged while the request head is being collected, and counted
+    fn log_request(&mut self, data: &[u8]) {
+        if self.request_log.as_mut().is_some_and(|log| log.feed(data)) {
+            self.request_log = None;
+        }
+        if let Some(traffic) = self.traffic {
+            traffic.add_frames_sent(self.frames_out.feed(data));
+        }
+    }
+
+    // Move whatever can be rewritten from `pending` to `out`
+    fn rewrite(&mut self) {
+        if !self.handshake_sent {
+            let Some(end) = self.pending.windows(4).position(|w| w == b"\r\n\r\n") else {
+                return;
+            };
+            self.out.extend(self.pending.drain(..end + 4));
+            self.handshake_sent = true;
+        }
+        while let Some(len) = unmask_frame(&self.pending, &mut self.out) {
+            self.pending.drain(..len);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Wire<S> {
+    // Write out everything already rewritten
+    fn poll_send_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out.drain(..n);
+            if let Some(traffic) = self.traffic {
+                traffic.add_sent(n);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Collects one side of the HTTP upgrade up to the blank line ending its head; what follows
+// is already WebSocket framing and isn't shown
+struct HandshakeLog {
+    label: &'static str,
+    bytes: Vec<u8>,
+}
+
+impl HandshakeLog {
+    fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            bytes: Vec::new(),
+        }
+    }
+
+    // Add bytes; true once the head is complete and has been printed
+    fn feed(&mut self, data: &[u8]) -> bool {
+        self.bytes.extend_from_slice(data);
+        match self.bytes.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => {
+                self.bytes.truncate(end + 4);
+                self.print();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Print verbatim; line endings are shown as the \r\n actually sent
+    fn print(&self) {
+        let text = String::from_utf8_lossy(&self.bytes).replace("\r\n", "\\r\\n\n");
+        crate::diag(&format!(
+            "--- raw handshake {} ({} bytes) ---\n{}",
+            self.label,
+            self.bytes.len(),
+            text.trim_end_matches('\n')
+        ));
+    }
+}
+
+// Counts the frames in one direction of the connection from their headers, skipping the
+// HTTP head before them; works on chunks split anywhere
+#[derive(Default)]
+struct FrameCounter {
+    head_done: bool,
+    head: Vec<u8>,
+    // Header bytes of the frame being read so far, then the payload bytes still to skip
+    header: Vec<u8>,
+    skip: u64,
+}
+
+impl FrameCounter {
+    // Frames whose header was completed by `data`
+    fn feed(&mut self, mut data: &[u8]) -> u64 {
+        if !self.head_done {
+            let start = self.head.len().saturating_sub(3);
+            self.head.extend_from_slice(data);
+            let Some(pos) = self.head[start..].windows(4).position(|w| w == b"\r\n\r\n") else {
+                return 0;
+            };
+            let end = start + pos + 4;
+            data = &data[data.len() - (self.head.len() - end)..];
+            self.head_done = true;
+            self.head = Vec::new();
+        }
+
+        let mut frames = 0;
+        while !data.is_empty() {
+            if self.skip > 0 {
+                let n = self.skip.min(data.len() as u64);
+                self.skip -= n;
+                data = &data[n as usize..];
+                continue;
+            }
+            self.header.push(data[0]);
+            data = &data[1..];
+            if let Some(payload_len) = header_payload_len(&self.header) {
+                frames += 1;
+                self.skip = payload_len;
+                self.header.clear();
+            }
+        }
+        frames
+    }
+}
+
+// Payload length once `header` holds a complete frame header (extended length and mask key
+// included), None while bytes are missing
+fn header_payload_len(header: &[u8]) -> Option<u64> {
+    let second = *header.get(1)?;
+    let mask_len = if second & 0x80 != 0 { 4 } else { 0 };
+    let (ext_len, payload_len) = match second & 0x7f {
+        126 => (
+            2,
+            u16::from_be_bytes(header.get(2..4)?.try_into().ok()?) as u64,
+        ),
+        127 => (8, u64::from_be_bytes(header.get(2..10)?.try_into().ok()?)),
+        len => (0, len as u64),
+    };
+    (header.len() == 2 + ext_len + mask_len).then_some(payload_len)
+}
+
+// Copy one complete frame from `buf` to `out` without its mask; returns the bytes consumed,
+// or None if the frame isn't complete yet
+fn unmask_frame(buf: &[u8], out: &mut Vec<u8>) -> Option<usize> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let masked = buf[1] & 0x80 != 0;
+    let (header_len, payload_len) = match buf[1] & 0x7f {
+        126 => (
+            4,
+            u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as usize,
+        ),
+        127 => (
+            10,
+            u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?) as usize,
+        ),
+        len => (2, len as usize),
+    };
+    let key_len = if masked { 4 } else { 0 };
+    let total = header_len + key_len + payload_len;
+    if buf.len() < total {
+        return None;
+    }
+
+    out.push(buf[0]);
+    out.push(buf[1] & 0x7f);
+    out.extend_from_slice(&buf[2..header_len]);
+    let payload = &buf[header_len + key_len..total];
+    if masked {
+        let key = &buf[header_len..header_len + 4];
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+    } else {
+        out.extend_from_slice(payload);
+    }
+    Some(total)
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Wire<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(traffic) = self.traffic {
+            let data = &buf.filled()[before..];
+            traffic.add_received(data.len());
+            traffic.add_frames_received(self.frames_in.feed(data));
+        }
+        if let Some(log) = &mut self.response_log {
+            let data = &buf.filled()[before..];
+            if data.is_empty() {
+                // EOF before the head was complete: show what did arrive
+                if !log.bytes.is_empty() {
+                    log.print();
+                }
+                self.response_log = None;
+            } else if log.feed(data) {
+                self.response_log = None;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Wire<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.unmask {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+            self.log_request(&buf[..n]);
+            if let Some(traffic) = self.traffic {
+                traffic.add_sent(n);
+            }
+            return Poll::Ready(Ok(n));
+        }
+        // Apply backpressure until earlier bytes are out, then take the whole buffer
+        ready!(self.poll_send_out(cx))?;
+        self.log_request(buf);
+        self.pending.extend_from_slice(buf);
+        self.rewrite();
+        if let Poll::Ready(Err(err)) = self.poll_send_out(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_out(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_out(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,18 +1,23 @@
+use std::future::Future;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::process::{Output, Stdio};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
+use assert_cmd::assert::OutputAssertExt;
 use assert_cmd::cargo::cargo_bin_cmd;
 use futures_util::{SinkExt, StreamExt};
 use predicates::str::contains;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use tokio::net::TcpListener;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
 use tokio_rustls::TlsAcceptor;
-use tokio_tungstenite::accept_hdr_async;
 use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async, accept_hdr_async};
 
 fn write_cert_files(temp_dir: &Path) -> (std::path::PathBuf, std::path::PathBuf, Vec<u8>, Vec<u8>) {
     let cert =
@@ -60,6 +65,8 @@ fn spawn_wss_server(
             let tls_stream = acceptor.accept(stream).await.unwrap();
 
             let cb_header = header_value.clone();
+            // The error type (an HTTP response) is tungstenite's, not ours to shrink
+            #[allow(clippy::result_large_err)]
             let callback = move |req: &Request, resp: Response| {
                 if let Some(storage) = &cb_header {
                     if let Some(value) = req.headers().get("x-test") {
@@ -103,6 +110,9 @@ fn spawn_wss_server(
             }
 
             ws_stream.send(Message::Close(None)).await.ok();
+            // Dropping the socket with the client's close unread would reset the connection and
+            // could discard frames the client hasn't read yet
+            while let Some(Ok(_)) = ws_stream.next().await {}
         });
     });
 
@@ -110,6 +120,69 @@ fn spawn_wss_server(
     (addr, handle)
 }
 
+// Plain ws:// server for a single connection; the handler owns the raw TCP stream
+fn spawn_ws_server<F, Fut>(handler: F) -> (SocketAddr, thread::JoinHandle<()>)
+where
+    F: FnOnce(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()>,
+{
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        let rt = Runtime::new().expect("runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+            let (stream, _) = listener.accept().await.unwrap();
+            handler(stream).await;
+        });
+    });
+
+    let addr = addr_rx.recv().unwrap();
+    (addr, handle)
+}
+
+// Read the raw HTTP upgrade request without consuming it, so it can still be accepted
+async fn peek_request(stream: &TcpStream) -> String {
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = stream.peek(&mut buf).await.unwrap();
+        let request = String::from_utf8_lossy(&buf[..n]).to_string();
+        if request.contains("\r\n\r\n") {
+            return request;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+// Run wscrab with stdin held open, so the session ends on the server's close rather than on EOF
+fn run_with_open_stdin(args: &[&str], input: &str) -> Output {
+    run_with_stdin_steps(args, &[(0, input)])
+}
+
+// Like run_with_open_stdin, but feed stdin in steps of (delay in ms, input)
+fn run_with_stdin_steps(args: &[&str], steps: &[(u64, &str)]) -> Output {
+    use std::io::Write;
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wscrab");
+
+    let mut stdin = child.stdin.take().unwrap();
+    for (delay, input) in steps {
+        thread::sleep(Duration::from_millis(*delay));
+        stdin.write_all(input.as_bytes()).unwrap();
+    }
+    let output = child.wait_with_output().expect("wait wscrab");
+    drop(stdin);
+    output
+}
+
 #[test]
 fn help_when_no_args() {
     let mut cmd = cargo_bin_cmd!("wscrab");
@@ -137,6 +210,72 @@ fn header_is_sent() {
     assert_eq!(header.lock().unwrap().clone(), Some("hello".to_string()));
 }
 
+#[test]
+fn handshake_only_closes_cleanly_without_input() {
+    let close = Arc::new(Mutex::new(None));
+    let captured = close.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        if let Some(Ok(Message::Close(frame))) = ws_stream.next().await {
+            *captured.lock().unwrap() = frame.map(|frame| u16::from(frame.code));
+        }
+    });
+
+    // stdin stays open: the probe must not wait for it
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--handshake-only"], "");
+    handle.join().unwrap();
+
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Connected"));
+    output.assert().success().stdout(contains(
+        "Handshake OK: 101 Switching Protocols, subprotocol: none",
+    ));
+    assert_eq!(*close.lock().unwrap(), Some(1000));
+}
+
+#[test]
+fn handshake_only_rejected_upgrade_exits_4() {
+    let (addr, handle) = spawn_ws_server(|mut stream| async move {
+        peek_request(&stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--handshake-only"], "");
+    handle.join().unwrap();
+
+    output.assert().code(4).stderr(contains("200 OK"));
+}
+
+#[test]
+fn print_handshake_latency_reports_milliseconds() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--handshake-only",
+            "--print-handshake-latency",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let latency = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("handshake: "))
+        .and_then(|value| value.strip_suffix("ms"))
+        .unwrap_or_else(|| panic!("no latency line: {stderr}"));
+    latency.parse::<u64>().unwrap();
+    output.assert().success();
+}
+
 #[test]
 fn no_check_allows_self_signed() {
     let temp = tempfile::tempdir().unwrap();
@@ -152,6 +291,35 @@ fn no_check_allows_self_signed() {
     handle.join().unwrap();
 }
 
+#[test]
+fn insecure_hosts_skips_verification_only_for_listed_hosts() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_pem_path, _der_path, cert_der, key_der) = write_cert_files(temp.path());
+
+    // The same self-signed certificate, reached as a listed and as an unlisted host
+    let (addr, handle) = spawn_wss_server(cert_der.clone(), key_der.clone(), false, None, None);
+    cargo_bin_cmd!("wscrab")
+        .arg("--connect")
+        .arg(format!("wss://localhost:{}", addr.port()))
+        .arg("--insecure-hosts")
+        .arg("dev.example,localhost")
+        .assert()
+        .success();
+    handle.join().unwrap();
+
+    let (addr, handle) = spawn_wss_server(cert_der, key_der, false, None, None);
+    cargo_bin_cmd!("wscrab")
+        .arg("--connect")
+        .arg(format!("wss://{addr}"))
+        .arg("--insecure-hosts")
+        .arg("dev.example,localhost")
+        .assert()
+        .failure()
+        .stderr(contains("invalid peer certificate"));
+    // The server's TLS accept fails along with the client
+    let _ = handle.join();
+}
+
 #[test]
 fn cert_pem_allows_self_signed() {
     let temp = tempfile::tempdir().unwrap();
@@ -204,6 +372,147 @@ fn show_ping_pong_prints_messages() {
     handle.join().unwrap();
 }
 
+#[test]
+fn ping_interval_sends_ping_payload() {
+    let pings = Arc::new(Mutex::new(Vec::new()));
+    let captured = pings.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if let Message::Ping(data) = message {
+                captured.lock().unwrap().push(data.clone());
+                ws_stream.send(Message::Pong(data)).await.unwrap();
+                ws_stream.send(Message::Close(None)).await.ok();
+            }
+        }
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--ping-interval",
+            "1",
+            "--ping-payload",
+            "hex:6b61",
+            "--show-ping-pong",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< Received pong (data: \"ka\")"));
+    assert_eq!(*pings.lock().unwrap(), vec![b"ka".to_vec()]);
+}
+
+#[test]
+fn inactivity_ping_waits_for_idle_connection() {
+    let pinged_while_active = Arc::new(Mutex::new(false));
+    let captured = pinged_while_active.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Active: a message every 300ms for 1.8s keeps the 1s timer from firing
+        let mut sent = 0;
+        while sent < 6 {
+            match tokio::time::timeout(Duration::from_millis(300), ws_stream.next()).await {
+                Ok(Some(Ok(Message::Ping(_)))) => *captured.lock().unwrap() = true,
+                Ok(_) => {}
+                Err(_) => {
+                    ws_stream.send(Message::Text("busy".into())).await.unwrap();
+                    sent += 1;
+                }
+            }
+        }
+        // Idle: the next thing to arrive is the ping
+        let message = tokio::time::timeout(Duration::from_secs(3), ws_stream.next()).await;
+        assert!(matches!(message, Ok(Some(Ok(Message::Ping(_))))));
+        ws_stream.send(Message::Close(None)).await.ok();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--inactivity-ping", "1"], "");
+    handle.join().unwrap();
+
+    output.assert().success();
+    assert!(!*pinged_while_active.lock().unwrap());
+}
+
+#[test]
+fn auto_pong_data_empty_sends_zero_length_pong() {
+    let pongs = Arc::new(Mutex::new(Vec::new()));
+    let captured = pongs.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Ping(b"abc".to_vec()))
+            .await
+            .unwrap();
+        if let Some(Ok(Message::Pong(data))) = ws_stream.next().await {
+            captured.lock().unwrap().push(data);
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+        // Anything still queued (a second pong) arrives before the close reply
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if let Message::Pong(data) = message {
+                captured.lock().unwrap().push(data);
+            }
+        }
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--auto-pong-data", "empty"], "");
+    handle.join().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(*pongs.lock().unwrap(), vec![Vec::<u8>::new()]);
+}
+
+#[test]
+fn challenge_response_answers_with_hmac() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // RFC 4231 test case 2
+        ws_stream
+            .send(Message::Text("what do ya want for nothing?".into()))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let Some(Ok(Message::Text(text))) = ws_stream.next().await else {
+                break;
+            };
+            captured.lock().unwrap().push(text);
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--challenge-response",
+            "hmac-sha256:Jefe",
+        ],
+        "hello\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("Challenge received, response sent"));
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            "hello"
+        ]
+    );
+}
+
 #[test]
 fn slash_ping_sends_control_frame() {
     let temp = tempfile::tempdir().unwrap();
@@ -226,3 +535,3357 @@ fn slash_ping_sends_control_frame() {
         Some("ping:hello".to_string())
     );
 }
+
+#[test]
+fn header_case_sensitive_keeps_casing() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("ws://{addr}"))
+        .arg("--header-case-sensitive")
+        .arg("--header")
+        .arg("X-MixedCase-Token:abc");
+
+    cmd.assert().success();
+    handle.join().unwrap();
+
+    let request = request.lock().unwrap().clone();
+    assert!(
+        request.contains("\r\nX-MixedCase-Token: abc\r\n"),
+        "{request}"
+    );
+}
+
+#[test]
+fn header_case_sensitive_overrides_upgrade_headers() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--header-case-sensitive",
+            "-H",
+            "Host: example.test",
+            "-H",
+            "X-MixedCase-Token: abc",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output.assert().success();
+    let request = request.lock().unwrap().clone();
+    assert!(request.contains("\r\nHost: example.test\r\n"), "{request}");
+    assert!(!request.contains(&format!("Host: {addr}")), "{request}");
+    assert!(
+        request.contains("\r\nX-MixedCase-Token: abc\r\n"),
+        "{request}"
+    );
+}
+
+#[test]
+fn trace_header_sends_and_prints_generated_id() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = cargo_bin_cmd!("wscrab")
+        .args(["-c", &addr.to_string(), "--trace-header", "X-Request-Id"])
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let id = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("x-request-id: "))
+        .unwrap_or_else(|| panic!("no trace line: {stderr}"));
+    assert_eq!(id.len(), 36, "{id}");
+    let request = request.lock().unwrap().clone();
+    assert!(
+        request.contains(&format!("\r\nx-request-id: {id}\r\n")),
+        "{request}"
+    );
+}
+
+#[test]
+fn handshake_header_order_follows_command_line() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("ws://{addr}"))
+        .arg("--handshake-header-order")
+        .args(["-H", "X-Zeta:1", "-H", "X-Alpha:2", "-H", "X-Zeta:3"]);
+
+    cmd.assert().success();
+    handle.join().unwrap();
+
+    let request = request.lock().unwrap().clone();
+    assert!(
+        request.contains("\r\nx-zeta: 1\r\nx-alpha: 2\r\nx-zeta: 3\r\n"),
+        "{request}"
+    );
+}
+
+#[test]
+fn resolve_maps_fake_hostname_to_local_server() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("ws://backend.wscrab.invalid:{}", addr.port()))
+        .args(["--resolve", "other.invalid:192.0.2.1"])
+        .args(["--resolve", "backend.wscrab.invalid:127.0.0.1"]);
+
+    cmd.assert().success();
+    handle.join().unwrap();
+
+    let request = request.lock().unwrap().clone();
+    assert!(
+        request.contains(&format!(
+            "\r\nHost: backend.wscrab.invalid:{}\r\n",
+            addr.port()
+        )),
+        "{request}"
+    );
+}
+
+#[test]
+fn slash_pause_holds_messages_until_resume() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Wait until the client is paused, then overflow the pause buffer by 5
+        ws_stream.next().await;
+        for i in 0..1005 {
+            ws_stream.send(Message::Text(i.to_string())).await.unwrap();
+        }
+        // Close once the client has resumed
+        ws_stream.next().await;
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let url = format!("ws://{addr}");
+    let output = run_with_stdin_steps(
+        &["--connect", &url, "--slash"],
+        &[(0, "/pause\nready\n"), (500, "/resume\ndone\n")],
+    );
+    handle.join().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("(5 older messages dropped while paused)"));
+    assert!(!stdout.contains("< 4\n"));
+    assert!(stdout.contains("< 5\n< 6\n"));
+    assert!(stdout.contains("< 1004\n> done"));
+}
+
+#[test]
+fn buffer_incoming_flushes_burst_with_offsets() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        for text in ["m1", "m2", "m3"] {
+            ws_stream.send(Message::Text(text.into())).await.unwrap();
+        }
+        // Enter is pressed meanwhile; m4 starts a new burst, printed at the end of the session
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        ws_stream.send(Message::Text("m4".into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let output = run_with_stdin_steps(
+        &["-c", &addr.to_string(), "--buffer-incoming"],
+        &[(500, "\n")],
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("[+0ms] < m1\n[+"))
+        .stdout(contains("ms] < m2\n[+"))
+        .stdout(contains("ms] < m3\n"))
+        .stdout(contains("[+0ms] < m4\n"));
+}
+
+#[test]
+fn auto_origin_is_derived_from_url() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(addr.to_string())
+        .arg("--auto-origin");
+
+    cmd.assert().success();
+    handle.join().unwrap();
+
+    let request = request.lock().unwrap().clone();
+    assert!(
+        request.contains(&format!("\r\nOrigin: http://{addr}\r\n")),
+        "{request}"
+    );
+}
+
+#[test]
+fn dedup_collapses_repeated_messages() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        for text in ["hb", "hb", "hb", "other", "hb", "hb"] {
+            ws_stream.send(Message::Text(text.into())).await.unwrap();
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let url = format!("ws://{addr}");
+    let output = run_with_open_stdin(&["--connect", &url, "--dedup"], "");
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< hb\n< hb (x3)\n< other\n< hb\n< hb (x2)\n"));
+}
+
+#[test]
+fn until_exits_on_matching_message() {
+    let closed = Arc::new(Mutex::new(false));
+    let captured = closed.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        for text in [
+            "booting",
+            r#"{"status":"starting"}"#,
+            r#"{"status":"ready"}"#,
+        ] {
+            ws_stream.send(Message::Text(text.into())).await.unwrap();
+        }
+        // The client should close on its own now
+        if let Some(Ok(Message::Close(_))) = ws_stream.next().await {
+            *captured.lock().unwrap() = true;
+        }
+    });
+
+    let url = format!("ws://{addr}");
+    let output = run_with_open_stdin(&["--connect", &url, "--until", r#""status":"ready""#], "");
+    handle.join().unwrap();
+
+    output.assert().success().stdout(contains(
+        "< {\"status\":\"starting\"}\n< {\"status\":\"ready\"}\n",
+    ));
+    assert!(*closed.lock().unwrap());
+}
+
+#[test]
+fn timeout_ends_busy_session() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Keep the connection busy until the client goes away
+        while ws_stream.send(Message::Text("tick".into())).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    });
+
+    let url = format!("ws://{addr}");
+    let started = std::time::Instant::now();
+    let output = run_with_open_stdin(&["--connect", &url, "--timeout", "1"], "");
+    handle.join().unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(10));
+    output
+        .assert()
+        .code(3)
+        .stdout(contains("< tick"))
+        .stderr(contains("session timed out after 1s"));
+}
+
+#[test]
+fn close_timeout_drops_connection_when_close_is_never_acked() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Swallow the close frame at the TCP level: no close comes back
+        let mut buf = [0u8; 1024];
+        while ws_stream
+            .get_mut()
+            .read(&mut buf)
+            .await
+            .is_ok_and(|n| n > 0)
+        {}
+    });
+
+    let started = std::time::Instant::now();
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--slash", "--close-timeout", "500"],
+        "/close\n",
+    );
+    handle.join().unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(3));
+    output
+        .assert()
+        .success()
+        .stderr(contains("No close from the server within 500ms"));
+}
+
+#[test]
+fn deadline_closes_session_at_wall_clock_time() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Idle until the client closes
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let deadline = (chrono::Utc::now() + chrono::TimeDelta::seconds(1)).to_rfc3339();
+    let started = std::time::Instant::now();
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--deadline", &deadline], "");
+    handle.join().unwrap();
+
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed > Duration::from_millis(500) && elapsed < Duration::from_secs(5),
+        "{elapsed:?}"
+    );
+    output
+        .assert()
+        .success()
+        .stderr(contains("Deadline reached"));
+
+    // A deadline in the past is refused up front
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.args([
+        "-c",
+        &addr.to_string(),
+        "--deadline",
+        "2020-01-01T00:00:00Z",
+    ])
+    .assert()
+    .failure()
+    .stderr(contains("is in the past"));
+}
+
+// Start wscrab with `fd` inherited as fd 3, stdin piped
+#[cfg(unix)]
+fn spawn_with_fd_3(fd: std::os::fd::RawFd, args: &[&str]) -> std::process::Child {
+    use std::os::unix::process::CommandExt;
+
+    let mut command = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"));
+    command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // SAFETY: dup2 and fcntl are async-signal-safe. The socket lands on fd 3 in the child;
+    // clearing CLOEXEC covers the case where it already was fd 3 (dup2 is then a no-op)
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(fd, 3) == -1 || libc::fcntl(3, libc::F_SETFD, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn().expect("spawn wscrab")
+}
+
+#[cfg(unix)]
+#[test]
+fn fd_runs_handshake_over_inherited_socket() {
+    use std::os::fd::AsRawFd;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let server_handle = thread::spawn(move || {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async move {
+            server.set_nonblocking(true).unwrap();
+            let stream = TcpStream::from_std(server).unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            ws.send(Message::Text("hello via fd".into())).await.unwrap();
+            ws.close(None).await.unwrap();
+        });
+    });
+
+    let mut child = spawn_with_fd_3(
+        client.as_raw_fd(),
+        &["--fd", "3", "--connect", "ws://fd-test/"],
+    );
+    drop(client);
+
+    // Keep stdin open so the session ends on the server's close, not on EOF
+    let stdin = child.stdin.take().unwrap();
+    let output = child.wait_with_output().expect("wait wscrab");
+    drop(stdin);
+    server_handle.join().unwrap();
+
+    output.assert().success().stdout(contains("< hello via fd"));
+}
+
+#[cfg(unix)]
+#[test]
+fn fd_is_not_reused_for_redirect() {
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut server, _) = listener.accept().unwrap();
+    let server_handle = thread::spawn(move || {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = server.read(&mut buf).unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&buf[..n]);
+        }
+        server
+            .write_all(b"HTTP/1.1 302 Found\r\nLocation: /next\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+    });
+
+    let child = spawn_with_fd_3(
+        client.as_raw_fd(),
+        &[
+            "--fd",
+            "3",
+            "--connect",
+            "ws://fd-test/",
+            "--max-redirects",
+            "1",
+        ],
+    );
+    drop(client);
+
+    let output = child.wait_with_output().expect("wait wscrab");
+    server_handle.join().unwrap();
+    output
+        .assert()
+        .failure()
+        .stderr(contains("Redirected to ws://fd-test/next"))
+        .stderr(contains("--fd 3 was used by the first connection"));
+}
+
+#[cfg(unix)]
+#[test]
+fn fd_refuses_unix_socket() {
+    use std::os::fd::AsRawFd;
+
+    let (client, _server) = std::os::unix::net::UnixStream::pair().unwrap();
+    let child = spawn_with_fd_3(
+        client.as_raw_fd(),
+        &["--fd", "3", "--connect", "ws://fd-test/"],
+    );
+    drop(client);
+
+    let output = child.wait_with_output().expect("wait wscrab");
+    output
+        .assert()
+        .failure()
+        .stderr(contains("--fd 3 is not a TCP socket"));
+}
+
+// Free local port for server modes (the port can't be reported back before the child starts)
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+// Start wscrab in a server mode and wait for its "Listening on port" line
+fn spawn_server_mode(args: &[&str]) -> std::process::Child {
+    use std::io::BufRead;
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"))
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wscrab server");
+    let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.starts_with("Listening on port"), "unexpected: {line}");
+    child.stdout = Some(stdout.into_inner());
+    child
+}
+
+#[test]
+fn echo_server_sends_messages_back() {
+    let port = free_port().to_string();
+    let server = spawn_server_mode(&["--echo-server", &port, "--once"]);
+
+    let url = format!("ws://127.0.0.1:{port}");
+    let output = run_with_open_stdin(&["-c", &url, "--until", "ping me"], "ping me\n");
+    output.assert().success().stdout(contains("< ping me"));
+
+    let server_output = server.wait_with_output().unwrap();
+    server_output
+        .assert()
+        .success()
+        .stdout(contains("Client connected"));
+}
+
+#[test]
+fn echo_server_prefixes_diagnostics_with_client_id() {
+    use std::io::Write;
+
+    let port = free_port().to_string();
+    let server = spawn_server_mode(&["--echo-server", &port, "--once", "--client-id", "srv"]);
+
+    // Not a WebSocket handshake, so the connection fails and the server reports it
+    let mut tcp = std::net::TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+    tcp.write_all(b"hello\r\n\r\n").unwrap();
+
+    let server_output = server.wait_with_output().unwrap();
+    server_output
+        .assert()
+        .success()
+        .stderr(contains("[srv] Client 127.0.0.1:"));
+}
+
+#[test]
+fn listen_broadcast_relays_between_clients() {
+    let port = free_port().to_string();
+    let mut server = spawn_server_mode(&["--listen", &port, "--broadcast"]);
+    let url = format!("ws://127.0.0.1:{port}");
+
+    let subscriber = {
+        let url = url.clone();
+        thread::spawn(move || run_with_open_stdin(&["-c", &url, "--until", "hi all"], ""))
+    };
+    thread::sleep(Duration::from_millis(500));
+    let publisher = run_with_open_stdin(&["-c", &url, "--until", "hi all"], "hi all\n");
+
+    publisher.assert().success();
+    subscriber
+        .join()
+        .unwrap()
+        .assert()
+        .success()
+        .stdout(contains("< hi all"));
+
+    server.kill().unwrap();
+    let server_output = server.wait_with_output().unwrap();
+    server_output
+        .assert()
+        .stdout(contains("(2 connected)"))
+        .stdout(contains("< hi all"));
+}
+
+#[test]
+fn role_server_listens_on_url_port() {
+    let port = free_port();
+    let url = format!("ws://127.0.0.1:{port}");
+    let mut server = spawn_server_mode(&["--role", "server", "-c", &url]);
+
+    // Stdin ends after the line, so the client closes once it is sent
+    let mut client = cargo_bin_cmd!("wscrab");
+    client
+        .args(["-c", &url])
+        .write_stdin("from client\n")
+        .assert()
+        .success();
+
+    server.kill().unwrap();
+    let server_output = server.wait_with_output().unwrap();
+    server_output.assert().stdout(contains("< from client"));
+}
+
+#[test]
+fn role_server_wss_requires_cert() {
+    let url = format!("wss://127.0.0.1:{}", free_port());
+    cargo_bin_cmd!("wscrab")
+        .args(["--role", "server", "-c", &url])
+        .assert()
+        .code(2)
+        .stderr(contains("--role server with a wss:// URL requires --cert"));
+}
+
+#[test]
+fn listen_wait_for_open_delivers_early_input() {
+    use std::io::Write;
+
+    let port = free_port().to_string();
+    let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"))
+        .args(["--listen", &port, "--wait-for-open"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wscrab server");
+    // Typed before anyone is connected
+    let mut stdin = server.stdin.take().unwrap();
+    stdin.write_all(b"early\n").unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let url = format!("ws://127.0.0.1:{port}");
+    let client = run_with_open_stdin(&["-c", &url, "--until", "early"], "");
+    client.assert().success().stdout(contains("< early"));
+
+    server.kill().unwrap();
+    drop(stdin);
+    server.wait_with_output().unwrap();
+}
+
+#[test]
+fn client_id_sets_header_and_prefixes_diagnostics() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--client-id",
+            "worker-7",
+            "--slash",
+        ],
+        "/bogus\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("[worker-7] error: Unrecognized slash command."));
+    let request = request.lock().unwrap().to_ascii_lowercase();
+    assert!(
+        request.contains("\r\nx-client-id: worker-7\r\n"),
+        "{request}"
+    );
+}
+
+#[test]
+fn allow_downgrade_retries_plain_ws() {
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        let rt = Runtime::new().expect("runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+            // The TLS ClientHello isn't an HTTP request, so this first handshake fails
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(accept_async(stream).await.is_err());
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Text("plain".into())).await.unwrap();
+            ws_stream.close(None).await.ok();
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let url = format!("wss://{addr}");
+    let output = run_with_open_stdin(&["-c", &url, "--allow-downgrade"], "");
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("retrying without TLS: ws://"))
+        .stdout(contains("< plain"));
+}
+
+#[test]
+fn allow_downgrade_never_follows_certificate_failure() {
+    let identity = rcgen::generate_simple_self_signed(["127.0.0.1".to_string()]).unwrap();
+    let cert_der = identity.cert.der().to_vec();
+    let key_der = identity.key_pair.serialize_der();
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        let rt = Runtime::new().expect("runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+            // A TLS server whose certificate the client doesn't trust
+            let config = rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![CertificateDer::from(cert_der)],
+                    PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_der)),
+                )
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            assert!(TlsAcceptor::from(Arc::new(config))
+                .accept(stream)
+                .await
+                .is_err());
+
+            // No plaintext retry may follow
+            let retry = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+            assert!(retry.is_err());
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let url = format!("wss://{addr}");
+    let output = run_with_open_stdin(&["-c", &url, "--allow-downgrade"], "");
+    handle.join().unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(!output.status.success());
+    assert!(!stderr.contains("retrying without TLS"), "{stderr}");
+    assert!(stderr.contains("invalid peer certificate"), "{stderr}");
+}
+
+#[test]
+fn print_url_shows_normalized_url() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("{addr}/feed"))
+        .arg("--print-url");
+
+    cmd.assert()
+        .success()
+        .stderr(contains(format!("Connecting to ws://{addr}/feed")));
+    handle.join().unwrap();
+}
+
+#[test]
+fn require_subprotocol_fails_when_server_ignores_it() {
+    // accept_async never selects a subprotocol
+    let ignoring_server = || {
+        spawn_ws_server(|stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        })
+    };
+
+    let (addr, handle) = ignoring_server();
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(addr.to_string())
+        .arg("--subprotocol")
+        .arg("chat.v2");
+    cmd.assert()
+        .success()
+        .stderr(contains("warning: server did not select a subprotocol"));
+    handle.join().unwrap();
+
+    let (addr, handle) = ignoring_server();
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(addr.to_string())
+        .arg("--subprotocol")
+        .arg("chat.v2")
+        .arg("--require-subprotocol");
+    cmd.assert().code(4).stderr(contains(
+        "server did not select a subprotocol (offered: chat.v2)",
+    ));
+    handle.join().unwrap();
+}
+
+#[test]
+fn no_warnings_suppresses_warnings() {
+    // accept_async never selects a subprotocol: a warning, or an error with --require-subprotocol
+    let ignoring_server = || {
+        spawn_ws_server(|stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        })
+    };
+
+    let (addr, handle) = ignoring_server();
+    let output = cargo_bin_cmd!("wscrab")
+        .args([
+            "-c",
+            &addr.to_string(),
+            "--subprotocol",
+            "chat.v2",
+            "--no-warnings",
+        ])
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    output.assert().success();
+    assert!(!stderr.contains("warning"), "{stderr}");
+
+    // Errors are not warnings: still printed
+    let (addr, handle) = ignoring_server();
+    let output = cargo_bin_cmd!("wscrab")
+        .args([
+            "-c",
+            &addr.to_string(),
+            "--subprotocol",
+            "chat.v2",
+            "--require-subprotocol",
+            "--no-warnings",
+        ])
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+    output.assert().code(4).stderr(contains(
+        "error: server did not select a subprotocol (offered: chat.v2)",
+    ));
+}
+
+#[test]
+fn drain_exits_after_burst_goes_quiet() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        for text in ["backlog 1", "backlog 2", "backlog 3"] {
+            ws_stream.send(Message::Text(text.into())).await.unwrap();
+        }
+        // Stay connected and quiet until the client closes
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--drain", "300"], "");
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< backlog 1\n< backlog 2\n< backlog 3\n"));
+}
+
+#[test]
+fn raw_output_writes_exact_bytes() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Binary(vec![0x00, 0x9f, 0xff, b'\n', 0x01]))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Text("tail".into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--raw-output"], "");
+    handle.join().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x00\x9f\xff\n\x01tail");
+}
+
+#[test]
+fn raw_output_does_not_echo_slash_commands() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if message == Message::Text("done".into()) {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--slash", "--raw-output"],
+        "/repeat 2 hi\n/rawtext 6869\n/cbor {\"a\": 1}\n/macro define m hi\n/macro run m\ndone\n",
+    );
+    handle.join().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+}
+
+#[test]
+fn output_framing_prefixes_each_message_with_its_length() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Binary(vec![0x00, 0xff, b'\n']))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Text("tail".into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--raw-output",
+            "--output-framing",
+            "length-prefixed",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        output.stdout,
+        b"\x00\x00\x00\x03\x00\xff\n\x00\x00\x00\x04tail"
+    );
+}
+
+#[test]
+fn output_split_writes_one_file_per_message() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Binary(vec![0x89, b'P', b'N', b'G']))
+            .await
+            .unwrap();
+        ws_stream
+            .send(Message::Binary(vec![0xff, 0xd8, 0xff]))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().join("captures");
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--output-split",
+            dir.to_str().unwrap(),
+            "--output-split-ext",
+            "img",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< (binary, 4 bytes) -> "));
+    assert_eq!(
+        std::fs::read(dir.join("000001.img")).unwrap(),
+        [0x89, b'P', b'N', b'G']
+    );
+    assert_eq!(
+        std::fs::read(dir.join("000002.img")).unwrap(),
+        [0xff, 0xd8, 0xff]
+    );
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+}
+
+#[test]
+fn output_raw_saves_exact_bytes_while_display_shows_hex() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Binary(vec![0x00, 0xff, b'A', 0x80]))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path().join("captures");
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--output-split",
+            dir.to_str().unwrap(),
+            "--output-raw",
+            "--binary-display",
+            "hex",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    output.assert().success().stdout(contains("< 00ff4180\n"));
+    assert!(!stdout.contains(" bytes) -> "), "{stdout}");
+    assert_eq!(
+        std::fs::read(dir.join("000001.bin")).unwrap(),
+        [0x00, 0xff, b'A', 0x80]
+    );
+}
+
+#[test]
+fn expand_placeholders_fills_header_from_url() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("ws://{addr}/rooms/1"))
+        .arg("-H")
+        .arg("X-Target:{host}:{port}{path}")
+        .arg("--expand-placeholders");
+
+    cmd.assert().success();
+    handle.join().unwrap();
+
+    let request = request.lock().unwrap().to_ascii_lowercase();
+    let expected = format!("\r\nx-target: {}:{}/rooms/1\r\n", addr.ip(), addr.port());
+    assert!(request.contains(&expected), "{request}");
+}
+
+#[test]
+fn pin_sha256_accepts_matching_cert_only() {
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use sha2::{Digest, Sha256};
+
+    let temp = tempfile::tempdir().unwrap();
+    let (_pem_path, _der_path, cert_der, key_der) = write_cert_files(temp.path());
+    let pin = BASE64_STANDARD.encode(Sha256::digest(&cert_der));
+
+    let (addr, handle) = spawn_wss_server(cert_der.clone(), key_der.clone(), false, None, None);
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("wss://{addr}"))
+        .arg("--pin-sha256")
+        .arg(&pin);
+    cmd.assert().success();
+    handle.join().unwrap();
+
+    // The server thread panics on the failed TLS accept, so it isn't joined
+    let (addr, _handle) = spawn_wss_server(cert_der, key_der, false, None, None);
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("wss://{addr}"))
+        .arg("--pin-sha256")
+        .arg(BASE64_STANDARD.encode([0u8; 32]));
+    cmd.assert().failure().stderr(contains(format!(
+        "certificate pin mismatch (server sha256: {pin})"
+    )));
+}
+
+#[test]
+fn slow_handshake_still_connects() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        // tungstenite's server rejects a trickled request as an attack, so wait for all of it
+        peek_request(&stream).await;
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Text("patient".into()))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--slow-handshake", "1"], "");
+    handle.join().unwrap();
+
+    output.assert().success().stdout(contains("< patient"));
+}
+
+#[test]
+fn ws_version_override_prints_supported_versions() {
+    let request = Arc::new(Mutex::new(String::new()));
+    let captured = request.clone();
+    let (addr, handle) = spawn_ws_server(move |mut stream| async move {
+        *captured.lock().unwrap() = peek_request(&stream).await;
+        stream
+            .write_all(
+                b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--ws-version", "8"], "");
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .failure()
+        .stderr(contains("Server supports Sec-WebSocket-Version: 13"));
+    let request = request.lock().unwrap();
+    assert!(request.contains("Sec-WebSocket-Version: 8\r\n"));
+    assert!(!request.contains("Sec-WebSocket-Version: 13"));
+}
+
+#[cfg(unix)]
+#[test]
+fn on_message_pipes_payload_through_command() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Text("hooked".into()))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Text("again".into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--on-message", "cat"], "");
+    handle.join().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    output.assert().success();
+    assert!(stdout.contains("hooked\nagain\n"));
+    assert!(!stdout.contains("< hooked"));
+}
+
+#[cfg(unix)]
+#[test]
+fn on_message_failure_warns_unless_fatal() {
+    let serve = || {
+        spawn_ws_server(|stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Text("one".into())).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        })
+    };
+
+    let (addr, handle) = serve();
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--on-message", "exit 3"], "");
+    handle.join().unwrap();
+    output
+        .assert()
+        .success()
+        .stderr(contains("warning: --on-message: command failed"));
+
+    let (addr, handle) = serve();
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--on-message",
+            "exit 3",
+            "--on-message-fatal",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+    output
+        .assert()
+        .failure()
+        .stderr(contains("--on-message: command failed"));
+}
+
+#[test]
+fn char_mode_forwards_input_without_newline() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Line mode would still be waiting for a newline; stdin stays open until we close
+        for _ in 0..2 {
+            let Some(Ok(Message::Text(text))) = ws_stream.next().await else {
+                break;
+            };
+            captured.lock().unwrap().push(text);
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_stdin_steps(
+        &["-c", &addr.to_string(), "--char-mode"],
+        &[(0, "ab"), (300, "c")],
+    );
+    handle.join().unwrap();
+
+    output.assert().success();
+    assert_eq!(*received.lock().unwrap(), vec!["ab", "c"]);
+}
+
+#[test]
+fn input_json_lines_skips_malformed_line() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            let done = text == "[1,2]";
+            captured.lock().unwrap().push(text);
+            if done {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--input-json-lines"],
+        "{\"a\": 1}\n{\"b\": \n[1, 2]\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("invalid JSON, not sent"));
+    assert_eq!(*received.lock().unwrap(), vec!["{\"a\":1}", "[1,2]"]);
+
+    // --strict-json ends the session at the bad line instead
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--input-json-lines",
+            "--strict-json",
+        ],
+        "not json\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .failure()
+        .stderr(contains("invalid JSON input"));
+}
+
+#[test]
+fn max_line_length_truncates_or_fails() {
+    let long_line = format!("{}\n", "x".repeat(1000));
+
+    // --truncate-input sends the first BYTES and goes on with the next line
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            let done = text == "next";
+            captured.lock().unwrap().push(text);
+            if done {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--max-line-length",
+            "10",
+            "--truncate-input",
+        ],
+        &format!("{long_line}next\n"),
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("input line cut to 10 bytes"));
+    assert_eq!(*received.lock().unwrap(), vec!["xxxxxxxxxx", "next"]);
+
+    // Without it the session ends with an error, nothing of the line sent
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            assert!(!message.is_text(), "{message:?}");
+        }
+    });
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--max-line-length", "10"],
+        &long_line,
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .failure()
+        .stderr(contains("input line longer than 10 bytes"));
+}
+
+#[test]
+fn input_prefix_strip_removes_leading_match() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            let mut received = captured.lock().unwrap();
+            received.push(text);
+            if received.len() == 3 {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--input-prefix-strip",
+            r"\[\d{2}:\d{2}\] ",
+        ],
+        // The last line has the pattern, but not at the start
+        "[12:00] first\n[12:01] second\nthird [12:02] \n",
+    );
+    handle.join().unwrap();
+
+    output.assert().success();
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec!["first", "second", "third [12:02] "]
+    );
+}
+
+#[test]
+fn timestamp_field_replays_original_gaps() {
+    let arrivals = Arc::new(Mutex::new(Vec::new()));
+    let captured = arrivals.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            let mut arrivals = captured.lock().unwrap();
+            arrivals.push((text, std::time::Instant::now()));
+            if arrivals.len() == 2 {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--timestamp-field",
+            r"^(\S+) ",
+            "--input-prefix-strip",
+            r"\S+ ",
+        ],
+        "2024-05-01T12:00:00.000Z first\n2024-05-01T12:00:00.600Z second\n",
+    );
+    handle.join().unwrap();
+
+    output.assert().success();
+    let arrivals = arrivals.lock().unwrap();
+    assert_eq!(arrivals[0].0, "first");
+    assert_eq!(arrivals[1].0, "second");
+    let gap = arrivals[1].1 - arrivals[0].1;
+    assert!(gap >= Duration::from_millis(500), "{gap:?}");
+}
+
+#[test]
+fn message_prefix_and_suffix_wrap_sent_lines() {
+    let received = Arc::new(Mutex::new(None));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        if let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            *captured.lock().unwrap() = Some(text);
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--message-prefix",
+            "STX|",
+            "--message-suffix",
+            "|ETX",
+        ],
+        "hello\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("> STX|hello|ETX"));
+    assert_eq!(received.lock().unwrap().as_deref(), Some("STX|hello|ETX"));
+}
+
+#[test]
+fn verify_depth_rejects_long_chain() {
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    // root -> intermediate 1 -> intermediate 2 -> leaf
+    let ca_params = || {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+    };
+    let root_key = KeyPair::generate().unwrap();
+    let root = ca_params().self_signed(&root_key).unwrap();
+    let int1_key = KeyPair::generate().unwrap();
+    let int1 = ca_params().signed_by(&int1_key, &root, &root_key).unwrap();
+    let int2_key = KeyPair::generate().unwrap();
+    let int2 = ca_params().signed_by(&int2_key, &int1, &int1_key).unwrap();
+    let leaf_key = KeyPair::generate().unwrap();
+    let leaf = CertificateParams::new(vec!["127.0.0.1".to_string()])
+        .unwrap()
+        .signed_by(&leaf_key, &int2, &int2_key)
+        .unwrap();
+
+    let temp = tempfile::tempdir().unwrap();
+    let root_path = temp.path().join("root.pem");
+    std::fs::write(&root_path, root.pem()).unwrap();
+
+    let chain = vec![leaf.der().clone(), int2.der().clone(), int1.der().clone()];
+    let key_der = leaf_key.serialize_der();
+    let serve = move || {
+        let (chain, key_der) = (chain.clone(), key_der.clone());
+        spawn_ws_server(move |stream| async move {
+            let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_der));
+            let config = rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(chain, key)
+                .unwrap();
+            let Ok(tls_stream) = TlsAcceptor::from(Arc::new(config)).accept(stream).await else {
+                return;
+            };
+            let mut ws_stream = accept_async(tls_stream).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        })
+    };
+
+    let (addr, handle) = serve();
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("wss://{addr}"))
+        .arg("--cert")
+        .arg(&root_path)
+        .arg("--verify-depth")
+        .arg("2");
+    cmd.assert().success();
+    handle.join().unwrap();
+
+    let (addr, handle) = serve();
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("wss://{addr}"))
+        .arg("--cert")
+        .arg(&root_path)
+        .arg("--verify-depth")
+        .arg("1");
+    cmd.assert().failure().stderr(contains(
+        "certificate chain has 2 intermediates, --verify-depth allows 1",
+    ));
+    handle.join().unwrap();
+}
+
+#[test]
+fn slash_connect_switches_server() {
+    let (addr_a, handle_a) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Text("from A".into()))
+            .await
+            .unwrap();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+    let (addr_b, handle_b) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Text("from B".into()))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let switch = format!("/connect {addr_b}\n");
+    let output = run_with_stdin_steps(
+        &["-c", &addr_a.to_string(), "--slash"],
+        &[(500, switch.as_str())],
+    );
+    handle_a.join().unwrap();
+    handle_b.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< from A"))
+        .stdout(contains("< from B"))
+        .stderr(contains(format!("Switching to ws://{addr_b}")));
+}
+
+#[test]
+fn close_code_is_explained() {
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        let frame = CloseFrame {
+            code: CloseCode::Protocol,
+            reason: "bad opcode".into(),
+        };
+        ws_stream.close(Some(frame)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string()], "");
+    handle.join().unwrap();
+
+    output.assert().success().stderr(contains(
+        "Disconnected (code: 1002 protocol error, reason: \"bad opcode\")",
+    ));
+}
+
+#[test]
+fn pretty_close_names_known_and_application_codes() {
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    for (code, expected) in [
+        (
+            1011,
+            "Close: 1011 Internal Error: server encountered an unexpected condition",
+        ),
+        (4001, "Close: 4001 application-defined"),
+    ] {
+        let (addr, handle) = spawn_ws_server(move |stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            let frame = CloseFrame {
+                code: CloseCode::from(code),
+                reason: "".into(),
+            };
+            ws_stream.close(Some(frame)).await.ok();
+        });
+
+        let output = run_with_open_stdin(&["-c", &addr.to_string(), "--pretty-close"], "");
+        handle.join().unwrap();
+
+        output.assert().success().stderr(contains(expected));
+    }
+}
+
+#[test]
+fn require_clean_close_distinguishes_abrupt_end() {
+    // Clean: the server sends a close frame
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.close(None).await.ok();
+    });
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--require-clean-close"], "");
+    handle.join().unwrap();
+    output.assert().success();
+
+    // Abrupt: the TCP connection just goes away
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let ws_stream = accept_async(stream).await.unwrap();
+        drop(ws_stream);
+    });
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--require-clean-close"], "");
+    handle.join().unwrap();
+    output
+        .assert()
+        .code(6)
+        .stderr(contains("connection ended without a closing handshake"));
+}
+
+#[test]
+fn assert_receive_passes_and_fails() {
+    let sending = |text: &'static str| {
+        spawn_ws_server(move |stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Text(text.into())).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        })
+    };
+
+    let (addr, handle) = sending("{\"status\":\"ready\"}");
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--assert-receive", "ready"], "");
+    handle.join().unwrap();
+    output.assert().success();
+
+    let (addr, handle) = sending("{\"status\":\"starting\"}");
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--assert-receive", "ready"], "");
+    handle.join().unwrap();
+    output.assert().code(5).stderr(contains(
+        "assertion failed: no incoming message contained \"ready\"",
+    ));
+}
+
+#[test]
+fn error_on_match_closes_and_fails() {
+    let closed = Arc::new(Mutex::new(false));
+    let captured = closed.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Text("{\"type\":\"hello\"}".into()))
+            .await
+            .unwrap();
+        ws_stream
+            .send(Message::Text("{\"type\":\"error\",\"code\":42}".into()))
+            .await
+            .unwrap();
+        // wscrab closes once --error-on-match matches
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if message.is_close() {
+                *captured.lock().unwrap() = true;
+                break;
+            }
+        }
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--error-on-match",
+            "\"type\":\"error\"",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    assert!(*closed.lock().unwrap());
+    output
+        .assert()
+        .code(7)
+        .stdout(contains(
+            "< {\"type\":\"hello\"}\n< {\"type\":\"error\",\"code\":42}\n",
+        ))
+        .stderr(contains(
+            "server sent an error message: {\"type\":\"error\",\"code\":42}",
+        ));
+}
+
+#[test]
+fn bind_sets_local_address() {
+    let peer = Arc::new(Mutex::new(None));
+    let captured = peer.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        *captured.lock().unwrap() = Some(stream.peer_addr().unwrap());
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let local = format!("127.0.0.1:{}", free_port());
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(addr.to_string())
+        .arg("--bind")
+        .arg(&local);
+
+    cmd.assert().success();
+    handle.join().unwrap();
+    assert_eq!(peer.lock().unwrap().unwrap().to_string(), local);
+}
+
+#[test]
+fn slash_repeat_sends_text_n_times() {
+    let count = Arc::new(Mutex::new(0));
+    let captured = count.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            match message {
+                Message::Text(text) if text == "load test" => *captured.lock().unwrap() += 1,
+                Message::Text(text) if text == "done" => break,
+                _ => {}
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--slash"],
+        "/repeat 50 load test\n/repeat 0 nope\ndone\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("error: Usage: /repeat <1-10000> <text>"));
+    assert_eq!(*count.lock().unwrap(), 50);
+}
+
+#[test]
+fn slash_macro_replays_recorded_lines() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            match message {
+                Message::Text(text) if text == "done" => break,
+                Message::Text(text) => captured.lock().unwrap().push(format!("text:{text}")),
+                Message::Ping(data) => captured
+                    .lock()
+                    .unwrap()
+                    .push(format!("ping:{}", String::from_utf8_lossy(&data))),
+                _ => {}
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--slash"],
+        "/macro define login {\"auth\": \"x\"}\n/macro add login /ping hb\n/macro run login\n/macro run login\ndone\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("> {\"auth\": \"x\"}"));
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![
+            "text:{\"auth\": \"x\"}",
+            "ping:hb",
+            "text:{\"auth\": \"x\"}",
+            "ping:hb"
+        ]
+    );
+}
+
+#[test]
+fn strip_ansi_removes_color_codes() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        let line = "\x1b[1;31mERROR\x1b[0m disk \x1b]0;title\x07full";
+        ws_stream.send(Message::Text(line.into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--strip-ansi"], "");
+    handle.join().unwrap();
+
+    assert!(!output.stdout.contains(&0x1b));
+    output
+        .assert()
+        .success()
+        .stdout(contains("< ERROR disk full\n"));
+}
+
+#[test]
+fn drop_incoming_binary_prints_only_text() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Binary(b"blob".to_vec()))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Text("hello".into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--drop-incoming",
+            "binary",
+            "--number",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(!stdout.contains("blob"), "{stdout}");
+    output.assert().success().stdout(contains("[1] < hello\n"));
+}
+
+#[test]
+fn trim_incoming_trims_before_printing_and_matching() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Text("  ready \n".into()))
+            .await
+            .unwrap();
+        // wscrab closes once --until matches
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if message.is_close() {
+                break;
+            }
+        }
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--trim-incoming",
+            "--until",
+            "^ready$",
+            "--assert-receive",
+            "ready",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output.assert().success().stdout(contains("< ready\n"));
+}
+
+#[test]
+fn number_prefixes_lines_in_both_directions() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        for _ in 0..2 {
+            let message = ws_stream.next().await.unwrap().unwrap();
+            ws_stream.send(message).await.unwrap();
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_stdin_steps(
+        &["-c", &addr.to_string(), "--number"],
+        &[(0, "one\n"), (300, "two\n")],
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("[1] > one\n[2] < one\n[3] > two\n[4] < two\n"));
+}
+
+#[test]
+fn echo_format_templates_are_honored() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        for _ in 0..2 {
+            let message = ws_stream.next().await.unwrap().unwrap();
+            ws_stream.send(message).await.unwrap();
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--local-echo-format",
+            "SENT #{n}: {msg}",
+            "--remote-echo-format",
+            "{ts} RECV #{n}: {msg}",
+        ],
+        "hi\n{n}\n",
+    );
+    handle.join().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    output.assert().success();
+    assert!(stdout.contains("SENT #1: hi\n"));
+    // Braces in the message itself are not placeholders
+    assert!(stdout.contains("SENT #2: {n}\n"));
+    let received: Vec<&str> = stdout.lines().filter(|l| l.contains(" RECV #")).collect();
+    assert_eq!(received.len(), 2);
+    let (ts, rest) = received[1].split_once(' ').unwrap();
+    assert!(chrono::DateTime::parse_from_rfc3339(ts).is_ok(), "{ts}");
+    assert_eq!(rest, "RECV #2: {n}");
+}
+
+// mTLS server config trusting a fresh CA, plus client identities `<name>.pem` (cert + key)
+// signed by it in `dir`; returns the identities' DER certs in order
+fn mtls_server_config(dir: &Path, names: &[&str]) -> (Arc<rustls::ServerConfig>, Vec<Vec<u8>>) {
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+
+    let (_pem_path, _der_path, server_cert_der, server_key_der) = write_cert_files(dir);
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().unwrap();
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let identities = names
+        .iter()
+        .map(|name| {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.distinguished_name.push(DnType::CommonName, *name);
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            std::fs::write(
+                dir.join(format!("{name}.pem")),
+                cert.pem() + &key.serialize_pem(),
+            )
+            .unwrap();
+            cert.der().to_vec()
+        })
+        .collect();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(ca.der().clone()).unwrap();
+    let client_verifier = rustls::server::WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .unwrap();
+    let server_config = Arc::new(
+        rustls::ServerConfig::builder()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(
+                vec![CertificateDer::from(server_cert_der)],
+                PrivateKeyDer::from(PrivatePkcs8KeyDer::from(server_key_der)),
+            )
+            .unwrap(),
+    );
+    (server_config, identities)
+}
+
+#[test]
+fn cert_mapping_file_selects_identity_by_host() {
+    let temp = tempfile::tempdir().unwrap();
+    let (server_config, identities) = mtls_server_config(temp.path(), &["alpha", "beta"]);
+    let mapping = temp.path().join("identities.map");
+    std::fs::write(
+        &mapping,
+        "# host = identity\nlocalhost = alpha.pem\n* = beta.pem\n",
+    )
+    .unwrap();
+
+    // Connect by name and by IP to the same server, recording which client cert it saw
+    let mut seen = Vec::new();
+    for host in ["localhost", "127.0.0.1"] {
+        let config = server_config.clone();
+        let presented = Arc::new(Mutex::new(None));
+        let captured = presented.clone();
+        let (addr, handle) = spawn_ws_server(move |stream| async move {
+            let tls_stream = TlsAcceptor::from(config).accept(stream).await.unwrap();
+            let cert = tls_stream.get_ref().1.peer_certificates().unwrap()[0].to_vec();
+            *captured.lock().unwrap() = Some(cert);
+            let mut ws_stream = accept_async(tls_stream).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        });
+
+        let mut cmd = cargo_bin_cmd!("wscrab");
+        cmd.arg("--connect")
+            .arg(format!("wss://{host}:{}", addr.port()))
+            .arg("--no-check")
+            .arg("--cert")
+            .arg(&mapping);
+        cmd.assert().success();
+        handle.join().unwrap();
+        seen.push(presented.lock().unwrap().take().unwrap());
+    }
+
+    assert_eq!(seen, identities);
+}
+
+#[test]
+fn no_mask_sends_unmasked_frames() {
+    use tokio_tungstenite::accept_async_with_config;
+    use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+    // A lenient server accepts the unmasked frame, a compliant one rejects it
+    let serve = |lenient: bool| {
+        let result = Arc::new(Mutex::new(None));
+        let captured = result.clone();
+        let (addr, handle) = spawn_ws_server(move |stream| async move {
+            let config = WebSocketConfig {
+                accept_unmasked_frames: lenient,
+                ..Default::default()
+            };
+            let mut ws_stream = accept_async_with_config(stream, Some(config))
+                .await
+                .unwrap();
+            let received = match ws_stream.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Err(err)) => format!("error: {err}"),
+                other => format!("{other:?}"),
+            };
+            *captured.lock().unwrap() = Some(received);
+            ws_stream.send(Message::Close(None)).await.ok();
+        });
+        (addr, handle, result)
+    };
+
+    for (lenient, expected) in [(true, "bare frame"), (false, "error:")] {
+        let (addr, handle, result) = serve(lenient);
+        let output = run_with_open_stdin(&["-c", &addr.to_string(), "--no-mask"], "bare frame\n");
+        handle.join().unwrap();
+
+        assert!(String::from_utf8_lossy(&output.stderr).contains("warning: --no-mask"));
+        let received = result.lock().unwrap().take().unwrap();
+        assert!(received.starts_with(expected), "{received}");
+    }
+}
+
+#[test]
+fn hexdump_prints_offset_hex_and_ascii() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        let mut data = b"Hello, world!\n".to_vec();
+        data.extend([0x00, 0x01, 0xff, b'A']);
+        ws_stream.send(Message::Binary(data)).await.unwrap();
+        ws_stream.send(Message::Text("plain".into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--hexdump"], "");
+    handle.join().unwrap();
+
+    output.assert().success().stdout(contains(concat!(
+        "< (binary, 18 bytes)\n",
+        "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 01  |Hello, world!...|\n",
+        "00000010  ff 41                                             |.A|\n",
+        "00000012\n",
+        "< plain\n",
+    )));
+}
+
+#[test]
+fn reconnect_on_idle_cycles_stale_connection() {
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        let rt = Runtime::new().expect("runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+            // Goes silent after one message, like a blackholed connection
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stale = accept_async(stream).await.unwrap();
+            stale.send(Message::Text("first".into())).await.unwrap();
+            while let Some(Ok(_)) = stale.next().await {}
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Text("fresh".into())).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--reconnect-on-idle", "1"], "");
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< first"))
+        .stdout(contains("< fresh"))
+        .stderr(contains("No message for 1s, reconnecting to ws://"));
+}
+
+#[test]
+fn slash_rawtext_sends_invalid_utf8_text_frame() {
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        assert_eq!(
+            ws_stream.next().await.unwrap().unwrap(),
+            Message::Text("hi".into())
+        );
+        // Like a compliant server: invalid UTF-8 in a text frame is a 1007 close
+        assert!(ws_stream.next().await.unwrap().is_err());
+        let frame = CloseFrame {
+            code: CloseCode::Invalid,
+            reason: "invalid utf-8".into(),
+        };
+        ws_stream.send(Message::Close(Some(frame))).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--slash"],
+        "/rawtext zz\n/rawtext 68 69\n/rawtext c328\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("error: Usage: /rawtext <hex bytes>"))
+        .stderr(contains("code: 1007"));
+}
+
+#[test]
+fn reconnect_retries_until_max() {
+    // Nothing listens on the port, so every attempt is refused
+    let url = format!("ws://127.0.0.1:{}", free_port());
+    let output = run_with_open_stdin(&["-c", &url, "--reconnect", "--reconnect-max", "2"], "");
+
+    output
+        .assert()
+        .failure()
+        .stderr(contains("reconnecting... attempt 1, next in 1s"))
+        .stderr(contains("reconnecting... attempt 2, next in 2s"))
+        .stderr(contains("giving up after 2 reconnect attempts"));
+}
+
+#[test]
+fn reconnect_backoff_fixed_keeps_delay() {
+    let url = format!("ws://127.0.0.1:{}", free_port());
+    let started = std::time::Instant::now();
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &url,
+            "--reconnect",
+            "--reconnect-max",
+            "3",
+            "--reconnect-backoff",
+            "fixed",
+        ],
+        "",
+    );
+    let elapsed = started.elapsed();
+
+    output
+        .assert()
+        .failure()
+        .stderr(contains("reconnecting... attempt 2, next in 1s"))
+        .stderr(contains("reconnecting... attempt 3, next in 1s"))
+        .stderr(contains("giving up after 3 reconnect attempts"));
+    // Exponential would wait 1 + 2 + 4 seconds
+    assert!(elapsed < Duration::from_secs(6), "took {elapsed:?}");
+}
+
+#[test]
+fn reconnect_delay_zero_still_waits() {
+    let url = format!("ws://127.0.0.1:{}", free_port());
+    let started = std::time::Instant::now();
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &url,
+            "--reconnect",
+            "--reconnect-max",
+            "3",
+            "--reconnect-delay",
+            "0",
+        ],
+        "",
+    );
+    let elapsed = started.elapsed();
+
+    output
+        .assert()
+        .failure()
+        .stderr(contains("reconnecting... attempt 3, next in 100ms"))
+        .stderr(contains("giving up after 3 reconnect attempts"));
+    assert!(elapsed >= Duration::from_millis(300), "took {elapsed:?}");
+}
+
+#[test]
+fn max_reconnects_per_minute_waits_at_cap() {
+    let url = format!("ws://127.0.0.1:{}", free_port());
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"))
+        .args([
+            "-c",
+            &url,
+            "--reconnect",
+            "--reconnect-max",
+            "3",
+            "--reconnect-backoff",
+            "fixed",
+            "--reconnect-delay",
+            "0",
+            "--max-reconnects-per-minute",
+            "2",
+        ])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wscrab");
+
+    // Without the cap all three attempts would fail at once and wscrab would give up
+    thread::sleep(Duration::from_secs(2));
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("reconnecting... attempt 2, next in 100ms"));
+    assert!(stderr.contains("Reconnect rate cap reached (2 per minute), waiting 60s"));
+    assert!(!stderr.contains("giving up"), "{stderr}");
+}
+
+#[cfg(unix)]
+#[test]
+fn summary_on_signal_prints_stats_and_keeps_running() {
+    use std::io::Write;
+
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Text("one".into())).await.unwrap();
+        ws_stream.send(Message::Text("two".into())).await.unwrap();
+        // Stay up until the client says it's done
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if message.is_text() {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"))
+        .args(["-c", &addr.to_string(), "--summary-on-signal"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wscrab");
+
+    thread::sleep(Duration::from_millis(500));
+    let status = std::process::Command::new("kill")
+        .args(["-USR1", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    thread::sleep(Duration::from_millis(300));
+    assert!(child.try_wait().unwrap().is_none());
+
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"done\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    drop(stdin);
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("Stats: sent: 0 messages, recv: 2 messages"));
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_still_prints_messages_sent_before_close() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Sent after the client's close is on its way, before the server has read it
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        ws_stream.send(Message::Text("late".into())).await.unwrap();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"))
+        .args(["-c", &addr.to_string()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wscrab");
+
+    thread::sleep(Duration::from_millis(400));
+    let status = std::process::Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let stdin = child.stdin.take().unwrap();
+    let output = child.wait_with_output().unwrap();
+    drop(stdin);
+    handle.join().unwrap();
+
+    output.assert().success().stdout(contains("< late\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn on_reconnect_runs_command_with_attempt_number() {
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        let rt = Runtime::new().expect("runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+            // The first connection is closed right away, the second one says hello
+            for attempt in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws_stream = accept_async(stream).await.unwrap();
+                if attempt == 1 {
+                    ws_stream.send(Message::Text("hello".into())).await.unwrap();
+                }
+                ws_stream.send(Message::Close(None)).await.ok();
+                while let Some(Ok(_)) = ws_stream.next().await {}
+            }
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--reconnect",
+            "--reconnect-delay",
+            "0",
+            "--on-reconnect",
+            "echo \"hook: reconnect $1\"",
+            "--until",
+            "hello",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("hook: reconnect 1\n"))
+        .stdout(contains("< hello"));
+}
+
+#[test]
+fn resilient_follows_redirect_and_reconnects() {
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        let rt = Runtime::new().expect("runtime");
+        rt.block_on(async move {
+            let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let back = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let back_addr = back.local_addr().unwrap();
+            addr_tx.send(front.local_addr().unwrap()).unwrap();
+
+            // Each connect is redirected to the backend, which drops the first connection
+            for attempt in 0..2 {
+                let (mut stream, _) = front.accept().await.unwrap();
+                peek_request(&stream).await;
+                let response =
+                    format!("HTTP/1.1 302 Found\r\nLocation: http://{back_addr}/feed\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+
+                let (stream, _) = back.accept().await.unwrap();
+                let mut ws_stream = accept_async(stream).await.unwrap();
+                if attempt == 1 {
+                    ws_stream.send(Message::Text("hello".into())).await.unwrap();
+                    while let Some(Ok(_)) = ws_stream.next().await {}
+                }
+            }
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--resilient", "--until", "hello"],
+        "",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< hello"))
+        .stderr(contains("Redirected to ws://"))
+        .stderr(contains("Connection lost, reconnecting"));
+}
+
+#[test]
+fn close_reason_file_is_sent_truncated() {
+    let temp = tempfile::tempdir().unwrap();
+    let path = temp.path().join("reason.txt");
+    std::fs::write(&path, "x".repeat(200)).unwrap();
+
+    let received = Arc::new(Mutex::new(None));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if let Message::Close(frame) = message {
+                *captured.lock().unwrap() =
+                    frame.map(|f| (u16::from(f.code), f.reason.to_string()));
+                break;
+            }
+        }
+    });
+
+    // Stdin ends right away: the EOF close carries the reason
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.args(["-c", &addr.to_string(), "--close-reason-file"])
+        .arg(&path)
+        .write_stdin("")
+        .assert()
+        .success()
+        .stderr(contains("close reason is 200 bytes, truncated to 123"));
+    handle.join().unwrap();
+
+    assert_eq!(
+        received.lock().unwrap().clone(),
+        Some((1000, "x".repeat(123)))
+    );
+}
+
+#[test]
+fn print_response_header_prints_server_set_value() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        // The error type (an HTTP response) is tungstenite's, not ours to shrink
+        #[allow(clippy::result_large_err)]
+        let callback = |_req: &Request, mut resp: Response| {
+            resp.headers_mut()
+                .insert("Set-Cookie", "session=abc123".parse().unwrap());
+            Ok(resp)
+        };
+        let mut ws_stream = accept_hdr_async(stream, callback).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--print-response-header",
+            "set-cookie",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("session=abc123\nConnected"));
+}
+
+#[test]
+fn every_jitter_keeps_rough_cadence() {
+    let arrivals = Arc::new(Mutex::new(Vec::new()));
+    let captured = arrivals.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            assert_eq!(text, "hb");
+            let mut arrivals = captured.lock().unwrap();
+            arrivals.push(std::time::Instant::now());
+            if arrivals.len() == 8 {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--every",
+            "100",
+            "--every-message",
+            "hb",
+            "--every-jitter",
+            "0.5",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output.assert().success().stdout(contains("> hb"));
+    // Intervals vary between 50 and 150 ms; on average they stay near 100 ms
+    let arrivals = arrivals.lock().unwrap();
+    assert_eq!(arrivals.len(), 8);
+    let average = (arrivals[7] - arrivals[0]) / 7;
+    assert!(
+        average > Duration::from_millis(40) && average < Duration::from_millis(250),
+        "{average:?}"
+    );
+}
+
+#[test]
+fn template_repeat_renders_each_send() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        let mut seqs = Vec::new();
+        let mut ids = Vec::new();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert!(value["ts"].as_u64().unwrap() > 0);
+            let id = value["id"].as_str().unwrap().to_string();
+            assert_eq!(id.len(), 36);
+            assert_eq!(&id[14..15], "4");
+            seqs.push(value["seq"].as_u64().unwrap());
+            ids.push(id);
+            if seqs.len() == 3 {
+                break;
+            }
+        }
+        assert_eq!(seqs, [1, 2, 3]);
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--template",
+            r#"{"seq":{n},"ts":{ts},"id":"{uuid}"}"#,
+            "--repeat",
+            "3",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output.assert().success().stdout(contains(r#"> {"seq":3,"#));
+}
+
+#[test]
+fn send_rate_ramp_sends_more_later() {
+    let arrivals = Arc::new(Mutex::new(Vec::new()));
+    let captured = arrivals.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        let started = std::time::Instant::now();
+        let window = Duration::from_millis(1200);
+        while let Ok(Some(Ok(Message::Text(_)))) =
+            tokio::time::timeout(window.saturating_sub(started.elapsed()), ws_stream.next()).await
+        {
+            captured.lock().unwrap().push(started.elapsed());
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--send-rate-ramp",
+            "5:50:1",
+            "--every-message",
+            "load",
+            "--raw-output",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    output.assert().success();
+    // Roughly 8 messages are due in the first 600 ms and 20 in the next
+    let arrivals = arrivals.lock().unwrap();
+    let split = Duration::from_millis(600);
+    let early = arrivals.iter().filter(|t| **t < split).count();
+    let late = arrivals.len() - early;
+    assert!(early > 0 && late > early, "early {early}, late {late}");
+}
+
+#[test]
+fn trust_system_store_uses_os_roots() {
+    let temp = tempfile::tempdir().unwrap();
+    let (pem_path, _der_path, cert_der, key_der) = write_cert_files(temp.path());
+    let (addr, handle) = spawn_wss_server(cert_der, key_der, false, None, None);
+
+    // SSL_CERT_FILE stands in for the OS store (honored by rustls-native-certs on every platform)
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("wss://{addr}"))
+        .arg("--trust-system-store")
+        .env("SSL_CERT_FILE", pem_path);
+
+    cmd.assert().success();
+    handle.join().unwrap();
+}
+
+#[test]
+fn json_pointer_extracts_nested_field() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        for text in [
+            r#"{"data":{"status":"ready","count":3}}"#,
+            r#"{"other":true}"#,
+            "not json",
+            r#"{"data":{"status":{"code":7}}}"#,
+        ] {
+            ws_stream.send(Message::Text(text.into())).await.unwrap();
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--json-pointer", "/data/status"],
+        "",
+    );
+    handle.join().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let received: Vec<&str> = stdout.lines().filter(|l| l.starts_with("< ")).collect();
+    assert_eq!(received, ["< ready", r#"< {"code":7}"#]);
+}
+
+#[cfg(unix)]
+#[test]
+fn input_fifo_is_reopened_at_eof() {
+    use std::io::Write;
+
+    let temp = tempfile::tempdir().unwrap();
+    let fifo = temp.path().join("input.fifo");
+    assert!(std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap()
+        .success());
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            captured.lock().unwrap().push(text);
+            if captured.lock().unwrap().len() == 2 {
+                break;
+            }
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let child = std::process::Command::new(assert_cmd::cargo::cargo_bin!("wscrab"))
+        .args(["-c", &addr.to_string(), "--input"])
+        .arg(&fifo)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("spawn wscrab");
+
+    // Two writers one after another; the first one's EOF must not end the session
+    for line in ["one\n", "two\n"] {
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&fifo).unwrap();
+        writer.write_all(line.as_bytes()).unwrap();
+        drop(writer);
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    let output = child.wait_with_output().unwrap();
+    handle.join().unwrap();
+
+    output.assert().success();
+    assert_eq!(*received.lock().unwrap(), ["one", "two"]);
+}
+
+#[test]
+fn conversation_waits_for_each_reply() {
+    let temp = tempfile::tempdir().unwrap();
+    let script = temp.path().join("conversation.txt");
+    std::fs::write(&script, "one\ntwo\n").unwrap();
+
+    // A slow echo: without the gate both lines would go out before the first reply
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            ws_stream.send(Message::Text(text)).await.unwrap();
+        }
+    });
+    let output = cargo_bin_cmd!("wscrab")
+        .args(["-c", &addr.to_string(), "--conversation"])
+        .arg(&script)
+        .write_stdin("")
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+    output
+        .assert()
+        .success()
+        .stdout(contains("> one\n< one\n> two\n< two\n"));
+
+    // A server that never answers
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(message)) = ws_stream.next().await {
+            if message.is_close() {
+                break;
+            }
+        }
+    });
+    let output = cargo_bin_cmd!("wscrab")
+        .args([
+            "-c",
+            &addr.to_string(),
+            "--conversation-timeout",
+            "300",
+            "--conversation",
+        ])
+        .arg(&script)
+        .write_stdin("")
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    output
+        .assert()
+        .code(3)
+        .stderr(contains("no reply within 300ms (--conversation-timeout)"));
+    assert!(!stdout.contains("> two"), "{stdout}");
+}
+
+#[test]
+fn conversation_counts_dropped_replies() {
+    let temp = tempfile::tempdir().unwrap();
+    let script = temp.path().join("conversation.txt");
+    std::fs::write(&script, "one\ntwo\n").unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            captured.lock().unwrap().push(text.clone());
+            ws_stream.send(Message::Text(text)).await.unwrap();
+        }
+    });
+    let output = cargo_bin_cmd!("wscrab")
+        .args([
+            "-c",
+            &addr.to_string(),
+            "--drop-incoming",
+            "text",
+            "--conversation",
+        ])
+        .arg(&script)
+        .timeout(Duration::from_secs(10))
+        .write_stdin("")
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    output.assert().success();
+    assert!(!stdout.contains("< one"), "{stdout}");
+    assert_eq!(*received.lock().unwrap(), ["one", "two"]);
+}
+
+#[test]
+fn replay_loops_reads_input_file_n_times() {
+    let temp = tempfile::tempdir().unwrap();
+    let script = temp.path().join("script.txt");
+    std::fs::write(&script, "one\ntwo\n").unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        while let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            captured.lock().unwrap().push(text);
+        }
+    });
+
+    let output = cargo_bin_cmd!("wscrab")
+        .args([
+            "-c",
+            &addr.to_string(),
+            "--replay-loops",
+            "3",
+            "--stats",
+            "--input",
+        ])
+        .arg(&script)
+        .write_stdin("")
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("Stats: sent: 6 messages,"));
+    assert_eq!(
+        *received.lock().unwrap(),
+        ["one", "two", "one", "two", "one", "two"]
+    );
+}
+
+#[test]
+fn show_raw_handshake_dumps_request_and_response() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--show-raw-handshake"], "");
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("--- raw handshake request ("))
+        .stderr(contains("GET / HTTP/1.1\\r\\n"))
+        .stderr(contains("Upgrade: websocket\\r\\n"))
+        .stderr(contains("--- raw handshake response ("))
+        .stderr(contains("HTTP/1.1 101 Switching Protocols\\r\\n"));
+}
+
+#[test]
+fn cert_reload_uses_rotated_cert_on_reconnect() {
+    for reload in [true, false] {
+        let temp = tempfile::tempdir().unwrap();
+        let (server_config, identities) = mtls_server_config(temp.path(), &["alpha", "beta"]);
+        let cert = temp.path().join("client.pem");
+        std::fs::copy(temp.path().join("alpha.pem"), &cert).unwrap();
+
+        let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+        let rotate = (temp.path().join("beta.pem"), cert.clone());
+        let handle = thread::spawn(move || {
+            let rt = Runtime::new().expect("runtime");
+            rt.block_on(async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                addr_tx.send(listener.local_addr().unwrap()).unwrap();
+
+                let mut seen = Vec::new();
+                for round in 0..2 {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let acceptor = TlsAcceptor::from(server_config.clone());
+                    let tls_stream = acceptor.accept(stream).await.unwrap();
+                    seen.push(tls_stream.get_ref().1.peer_certificates().unwrap()[0].to_vec());
+                    let mut ws_stream = accept_async(tls_stream).await.unwrap();
+                    if round == 0 {
+                        // Rotate while the client waits to reconnect
+                        std::fs::copy(&rotate.0, &rotate.1).unwrap();
+                        ws_stream.send(Message::Close(None)).await.ok();
+                    } else {
+                        ws_stream.send(Message::Text("done".into())).await.unwrap();
+                    }
+                    while let Some(Ok(_)) = ws_stream.next().await {}
+                }
+                seen
+            })
+        });
+        let addr = addr_rx.recv().unwrap();
+
+        let url = format!("wss://{addr}");
+        let cert = cert.to_str().unwrap();
+        let mut args = vec!["-c", &url, "--no-check", "--cert", cert, "--reconnect"];
+        args.extend(["--until", "done"]);
+        if reload {
+            args.push("--cert-reload");
+        }
+        let output = run_with_open_stdin(&args, "");
+        let seen = handle.join().unwrap();
+
+        output.assert().success();
+        let expected = if reload {
+            &identities[1]
+        } else {
+            &identities[0]
+        };
+        assert_eq!(seen[0], identities[0]);
+        assert_eq!(&seen[1], expected);
+    }
+}
+
+#[test]
+fn status_line_prints_summary_when_not_a_terminal() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        if let Some(Ok(message)) = ws_stream.next().await {
+            ws_stream.send(message).await.unwrap();
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--status-line"], "hello\n");
+    handle.join().unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success());
+    assert!(stderr.contains("Traffic: sent "), "{stderr}");
+    assert!(!stderr.contains("\x1b[2K"), "{stderr}");
+}
+
+#[test]
+fn replay_unsent_resends_after_mid_send_drop() {
+    use tokio_tungstenite::accept_async_with_config;
+    use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+    // Too big to fit in the socket buffers, so the send is still in progress when the server drops
+    const LEN: usize = 32 * 1024 * 1024;
+
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    let handle = thread::spawn(move || {
+        let rt = Runtime::new().expect("runtime");
+        rt.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addr_tx.send(listener.local_addr().unwrap()).unwrap();
+            let config = WebSocketConfig {
+                max_message_size: None,
+                max_frame_size: None,
+                ..Default::default()
+            };
+
+            // Don't read, then drop with unread data (connection reset)
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws_stream = accept_async_with_config(stream, Some(config))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            drop(ws_stream);
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = accept_async_with_config(stream, Some(config))
+                .await
+                .unwrap();
+            let Some(Ok(Message::Text(text))) = ws_stream.next().await else {
+                panic!("no replayed message");
+            };
+            let reply = format!("got {}", text.len());
+            ws_stream.send(Message::Text(reply)).await.unwrap();
+            while let Some(Ok(_)) = ws_stream.next().await {}
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let line = "x".repeat(LEN) + "\n";
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--reconnect",
+            "--replay-unsent",
+            "--until",
+            "^got",
+        ],
+        &line,
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("Replaying 1 unsent message"))
+        .stdout(contains(format!("< got {LEN}")));
+}
+
+#[test]
+fn print_cipher_shows_tls_version_and_suite() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_pem_path, _der_path, cert_der, key_der) = write_cert_files(temp.path());
+    let (addr, handle) = spawn_wss_server(cert_der, key_der, false, None, None);
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.arg("--connect")
+        .arg(format!("wss://{addr}"))
+        .arg("--no-check")
+        .arg("--print-cipher");
+
+    cmd.assert()
+        .success()
+        .stderr(predicates::str::is_match(r"TLS: TLS1\.3 TLS13_\w+").unwrap());
+    handle.join().unwrap();
+}
+
+#[test]
+fn emit_connect_event_describes_wss_connection() {
+    let temp = tempfile::tempdir().unwrap();
+    let (_pem_path, _der_path, cert_der, key_der) = write_cert_files(temp.path());
+    let (addr, handle) = spawn_wss_server(cert_der, key_der, false, None, None);
+
+    let url = format!("wss://{addr}");
+    let output = cargo_bin_cmd!("wscrab")
+        .args(["--connect", &url, "--no-check", "--emit-connect-event"])
+        .output()
+        .unwrap();
+    handle.join().unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let line = stdout
+        .lines()
+        .find(|line| line.starts_with('{'))
+        .unwrap_or_else(|| panic!("no event: {stdout}"));
+    let event: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(event["event"], "connected");
+    assert_eq!(event["url"], url);
+    assert_eq!(event["peer"], addr.to_string());
+    assert_eq!(event["tls_version"], "TLS1.3");
+    assert!(event["tls_cipher"].as_str().unwrap().starts_with("TLS13_"));
+    assert!(event["subprotocol"].is_null());
+    assert!(event["handshake_ms"].is_u64());
+}
+
+#[test]
+fn grep_filters_incoming_messages() {
+    for (invert, expected) in [
+        (false, ["< error: disk full", "< error: binary"]),
+        (true, ["< info: started", "< heartbeat"]),
+    ] {
+        let (addr, handle) = spawn_ws_server(|stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            for text in ["info: started", "error: disk full", "heartbeat"] {
+                ws_stream.send(Message::Text(text.into())).await.unwrap();
+            }
+            let binary = Message::Binary(b"error: binary".to_vec());
+            ws_stream.send(binary).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        });
+
+        let mut args = vec![
+            "-c".to_string(),
+            addr.to_string(),
+            "--grep".into(),
+            "^error".into(),
+        ];
+        if invert {
+            args.push("--grep-invert".into());
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let output = run_with_open_stdin(&args, "");
+        handle.join().unwrap();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let received: Vec<&str> = stdout.lines().filter(|l| l.starts_with("< ")).collect();
+        assert_eq!(received, expected);
+    }
+}
+
+#[test]
+fn smart_display_prints_utf8_binary_as_text() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Binary("héllo".as_bytes().to_vec()))
+            .await
+            .unwrap();
+        ws_stream
+            .send(Message::Binary(b"Hi\x00\xff".to_vec()))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(&["-c", &addr.to_string(), "--smart-display"], "");
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< héllo\n"))
+        .stdout(contains("< (binary, 4 bytes)\n"))
+        .stdout(contains("00000000  48 69 00 ff"));
+}
+
+#[test]
+fn payload_size_limit_prints_placeholder() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        ws_stream
+            .send(Message::Text("x".repeat(3 * 1024 * 1024)))
+            .await
+            .unwrap();
+        ws_stream.send(Message::Text("small".into())).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--payload-size-limit",
+            "1024",
+            "--stats",
+        ],
+        "",
+    );
+    handle.join().unwrap();
+
+    assert!(output.stdout.len() < 1024);
+    output
+        .assert()
+        .success()
+        .stdout(contains("< [message omitted: 3.0 MiB]\n< small\n"))
+        .stderr(contains("recv: 2 messages"));
+}
+
+#[test]
+fn binary_display_modes_render_same_bytes() {
+    let data = b"Hi\x00\xff".to_vec();
+    let cases: [(&str, &[u8]); 5] = [
+        ("text", "< Hi\0\u{fffd}\n".as_bytes()),
+        ("hex", b"< 486900ff\n"),
+        ("base64", b"< SGkA/w==\n"),
+        (
+            "hexdump",
+            b"00000000  48 69 00 ff                                       |Hi..|\n",
+        ),
+        ("raw", b"Hi\x00\xff"),
+    ];
+    for (mode, expected) in cases {
+        let payload = data.clone();
+        let (addr, handle) = spawn_ws_server(move |stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Binary(payload)).await.unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        });
+
+        let output = run_with_open_stdin(&["-c", &addr.to_string(), "--binary-display", mode], "");
+        handle.join().unwrap();
+
+        assert!(output.status.success());
+        assert!(
+            output.stdout.windows(expected.len()).any(|w| w == expected),
+            "{mode}: {:?}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+}
+
+#[test]
+fn output_format_renders_text_and_binary_alike() {
+    let cases = [
+        ("text", "< Hi\n< Hi\0\u{fffd}\n"),
+        (
+            "json",
+            "{\"data\":\"Hi\",\"size\":2,\"type\":\"text\"}\n\
+             {\"data\":\"SGkA/w==\",\"size\":4,\"type\":\"binary\"}\n",
+        ),
+        ("hex", "< 4869\n< 486900ff\n"),
+        ("base64", "< SGk=\n< SGkA/w==\n"),
+    ];
+    for (format, expected) in cases {
+        let (addr, handle) = spawn_ws_server(|stream| async move {
+            let mut ws_stream = accept_async(stream).await.unwrap();
+            ws_stream.send(Message::Text("Hi".into())).await.unwrap();
+            ws_stream
+                .send(Message::Binary(b"Hi\x00\xff".to_vec()))
+                .await
+                .unwrap();
+            ws_stream.send(Message::Close(None)).await.ok();
+        });
+
+        let output = run_with_open_stdin(&["-c", &addr.to_string(), "--output-format", format], "");
+        handle.join().unwrap();
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains(expected), "{format}: {stdout:?}");
+    }
+}
+
+#[test]
+fn cbor_round_trips_a_map() {
+    let received = Arc::new(Mutex::new(None));
+    let captured = received.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Echo the encoded map back, then something that isn't CBOR
+        if let Some(Ok(Message::Binary(data))) = ws_stream.next().await {
+            *captured.lock().unwrap() = Some(data.clone());
+            ws_stream.send(Message::Binary(data)).await.unwrap();
+        }
+        ws_stream.send(Message::Binary(vec![0xff])).await.unwrap();
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--cbor", "--slash"],
+        "/cbor {\"a\": 1, \"b\": [true, null, -2]}\n",
+    );
+    handle.join().unwrap();
+
+    // {"a": 1, "b": [true, null, -2]}: map(2), "a", 1, "b", array(3), true, null, -2
+    let expected = [0xa2, 0x61, b'a', 0x01, 0x61, b'b', 0x83, 0xf5, 0xf6, 0x21];
+    assert_eq!(received.lock().unwrap().as_deref(), Some(&expected[..]));
+    output
+        .assert()
+        .success()
+        .stdout(contains(r#"> {"a":1,"b":[true,null,-2]}"#))
+        .stdout(contains(
+            "< {\n  \"a\": 1,\n  \"b\": [\n    true,\n    null,\n    -2\n  ]\n}\n",
+        ))
+        .stdout(contains("< ff\n"));
+}
+
+#[test]
+fn after_connect_delay_holds_first_send() {
+    let elapsed = Arc::new(Mutex::new(None));
+    let captured = elapsed.clone();
+    let (addr, handle) = spawn_ws_server(move |stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        let connected = std::time::Instant::now();
+        if let Some(Ok(Message::Text(text))) = ws_stream.next().await {
+            *captured.lock().unwrap() = Some((text, connected.elapsed()));
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--after-connect-delay", "500"],
+        "early\n",
+    );
+    handle.join().unwrap();
+
+    output.assert().success();
+    let (text, waited) = elapsed.lock().unwrap().take().unwrap();
+    assert_eq!(text, "early");
+    assert!(waited >= Duration::from_millis(450), "{waited:?}");
+}
+
+#[test]
+fn count_frames_reports_fragments() {
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        assert_eq!(
+            ws_stream.next().await.unwrap().unwrap(),
+            Message::Text("hi".into())
+        );
+        // One text message in three fragments
+        for (part, opcode, last) in [
+            ("Hel", Data::Text, false),
+            ("lo ", Data::Continue, false),
+            ("world", Data::Continue, true),
+        ] {
+            let frame = Frame::message(part.as_bytes().to_vec(), OpCode::Data(opcode), last);
+            ws_stream.send(Message::Frame(frame)).await.unwrap();
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    let output = run_with_open_stdin(
+        &["-c", &addr.to_string(), "--stats", "--count-frames"],
+        "hi\n",
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains("< Hello world"))
+        .stderr(contains("Stats: sent: 1 messages / "))
+        .stderr(contains("recv: 1 messages / 4 frames"));
+}
+
+#[test]
+fn compression_stats_compares_payload_and_wire_bytes() {
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        if let Some(Ok(message)) = ws_stream.next().await {
+            ws_stream.send(message).await.unwrap();
+        }
+        ws_stream.send(Message::Close(None)).await.ok();
+    });
+
+    // 10000 identical bytes: as compressible as it gets, but nothing compresses it on the way
+    let line = format!("{}\n", "a".repeat(10000));
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--raw-output",
+            "--compression-stats",
+        ],
+        &line,
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stderr(contains("Compression: sent 9.8 KiB payload, 9."))
+        .stderr(contains("; recv 9.8 KiB payload, 9."))
+        .stderr(contains("(ratio 0.9"));
+}
+
+#[test]
+fn listen_with_cert_serves_wss() {
+    let temp = tempfile::tempdir().unwrap();
+    let (cert_only, _der_path, _cert_der, _key_der) = write_cert_files(temp.path());
+    let port = free_port().to_string();
+
+    let mut cmd = cargo_bin_cmd!("wscrab");
+    cmd.args(["--listen", &port, "--cert"]).arg(&cert_only);
+    cmd.assert()
+        .failure()
+        .stderr(contains("has no private key"));
+
+    let identity = rcgen::generate_simple_self_signed(["127.0.0.1".to_string()]).unwrap();
+    let pem = temp.path().join("server.pem");
+    std::fs::write(
+        &pem,
+        identity.cert.pem() + &identity.key_pair.serialize_pem(),
+    )
+    .unwrap();
+    let pem = pem.to_str().unwrap();
+    let mut server = spawn_server_mode(&["--listen", &port, "--broadcast", "--cert", pem]);
+
+    // --broadcast relays the message back to its sender
+    let url = format!("wss://127.0.0.1:{port}");
+    let client = run_with_open_stdin(&["-c", &url, "--no-check", "--until", "hello"], "hello\n");
+    client.assert().success().stdout(contains("< hello"));
+
+    server.kill().unwrap();
+    let server_output = server.wait_with_output().unwrap();
+    server_output.assert().stdout(contains("< hello"));
+}
+
+#[test]
+fn max_queued_sends_delivers_everything_to_slow_reader() {
+    const LINES: usize = 20;
+
+    let (addr, handle) = spawn_ws_server(|stream| async move {
+        let mut ws_stream = accept_async(stream).await.unwrap();
+        // Fall behind so the client's queue fills up and its input is paused
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut received = Vec::new();
+        while received.len() < LINES {
+            match ws_stream.next().await {
+                Some(Ok(Message::Text(text))) => received.push(text),
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        let in_order = received
+            .iter()
+            .enumerate()
+            .all(|(i, text)| text.starts_with(&format!("{i}:")));
+        let reply = format!("got {} in order: {in_order}", received.len());
+        ws_stream.send(Message::Text(reply)).await.unwrap();
+        while let Some(Ok(_)) = ws_stream.next().await {}
+    });
+
+    let input: String = (0..LINES)
+        .map(|i| format!("{i}:{}\n", "x".repeat(512 * 1024)))
+        .collect();
+    let output = run_with_open_stdin(
+        &[
+            "-c",
+            &addr.to_string(),
+            "--max-queued-sends",
+            "4",
+            "--until",
+            "^got",
+            // No echo of the sent lines: stdout is only read once all input is written
+            "--raw-output",
+        ],
+        &input,
+    );
+    handle.join().unwrap();
+
+    output
+        .assert()
+        .success()
+        .stdout(contains(format!("got {LINES} in order: true")));
+}